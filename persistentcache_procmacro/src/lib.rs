@@ -20,7 +20,9 @@ extern crate futures_await_quote as quote;
 extern crate futures_await_syn as syn;
 extern crate proc_macro;
 
-use proc_macro::TokenStream;
+use proc_macro::{Delimiter, Group, Spacing, TokenStream, TokenTree};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use syn::*;
 
 #[derive(Debug)]
@@ -33,10 +35,20 @@ struct Function {
     inputs: delimited::Delimited<FnArg, tokens::Comma>,
     output: FunctionRetTy,
     fn_token: tokens::Fn_,
+    /// Patterns of the arguments marked with `#[cache_ignore]`, without whitespace
+    ignored: Vec<String>,
+    /// Tokens of the statements in the body, which keep their spans unlike the parsed `block`
+    body: TokenStream,
 }
 
 impl Function {
     fn parse(func: TokenStream) -> Self {
+        // Attributes on arguments cannot be parsed, therefore they need to be removed first.
+        let (func, ignored) = strip_ignored_args(func);
+        let body = match func.clone().into_iter().last() {
+            Some(TokenTree::Group(body)) => body.stream(),
+            _ => panic!("Expected the body of a function"),
+        };
         let Item { node, attrs } = syn::parse(func.clone()).unwrap();
         let ItemFn {
             ident,
@@ -64,18 +76,170 @@ impl Function {
             inputs,
             output,
             fn_token,
+            ignored,
+            body,
+        }
+    }
+}
+
+/// Removes all `#[cache_ignore]` attributes from the arguments of the function `func` and returns
+/// the remaining function alongside the patterns of the ignored arguments without whitespace. Only
+/// the argument list is touched, all other tokens keep their spans.
+fn strip_ignored_args(func: TokenStream) -> (TokenStream, Vec<String>) {
+    let mut ignored = vec![];
+    let mut after_fn = false;
+    let mut done = false;
+    let tokens: Vec<TokenTree> = func
+        .into_iter()
+        .map(|token| match token {
+            TokenTree::Ident(ref ident) if !done && ident.to_string() == "fn" => {
+                after_fn = true;
+                token.clone()
+            }
+            // The first parenthesized group after `fn` holds the arguments
+            TokenTree::Group(ref group)
+                if after_fn && !done && group.delimiter() == Delimiter::Parenthesis =>
+            {
+                done = true;
+                let mut args = Group::new(
+                    Delimiter::Parenthesis,
+                    strip_ignored_in_args(group.stream(), &mut ignored),
+                );
+                args.set_span(group.span());
+                TokenTree::Group(args)
+            }
+            token => token,
+        })
+        .collect();
+    (tokens.into_iter().collect(), ignored)
+}
+
+/// Removes all `#[cache_ignore]` attributes from the argument list `args` and pushes the patterns
+/// of the ignored arguments to `ignored`.
+fn strip_ignored_in_args(args: TokenStream, ignored: &mut Vec<String>) -> TokenStream {
+    let tokens: Vec<TokenTree> = args.into_iter().collect();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < tokens.len() {
+        if is_cache_ignore(&tokens[i..]) {
+            i += 2;
+            let start = i;
+            // The pattern ends at the first `:` which is not part of a path separator `::`;
+            // nested brackets are single token trees.
+            while i < tokens.len() && !is_type_colon(&tokens[i..]) {
+                if is_path_separator(&tokens[i..]) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            let pattern: TokenStream = tokens[start..i].iter().cloned().collect();
+            ignored.push(without_whitespace(&pattern.to_string()));
+            out.extend(tokens[start..i].iter().cloned());
+        } else {
+            out.push(tokens[i].clone());
+            i += 1;
+        }
+    }
+    out.into_iter().collect()
+}
+
+/// Checks whether `tokens` start with `#[cache_ignore]`.
+fn is_cache_ignore(tokens: &[TokenTree]) -> bool {
+    match (tokens.first(), tokens.get(1)) {
+        (Some(TokenTree::Punct(hash)), Some(TokenTree::Group(group))) => {
+            hash.as_char() == '#'
+                && group.delimiter() == Delimiter::Bracket
+                && without_whitespace(&group.stream().to_string()) == "cache_ignore"
+        }
+        _ => false,
+    }
+}
+
+/// Checks whether `tokens` start with `::`.
+fn is_path_separator(tokens: &[TokenTree]) -> bool {
+    match (tokens.first(), tokens.get(1)) {
+        (Some(TokenTree::Punct(first)), Some(TokenTree::Punct(second))) => {
+            first.as_char() == ':' && first.spacing() == Spacing::Joint && second.as_char() == ':'
         }
+        _ => false,
+    }
+}
+
+/// Checks whether `tokens` start with the `:` separating a pattern from its type.
+fn is_type_colon(tokens: &[TokenTree]) -> bool {
+    match tokens.first() {
+        Some(TokenTree::Punct(colon)) => colon.as_char() == ':' && !is_path_separator(tokens),
+        _ => false,
+    }
+}
+
+fn without_whitespace(s: &str) -> String {
+    s.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+/// Splits the arguments of `#[persistent_cache(...)]` into pairs of names and values, e.g.
+/// `key = |a, b| a + b` into `("key", "|a, b| a + b")`.
+fn parse_options(attr: &str) -> Vec<(String, String)> {
+    let attr = attr.trim();
+    // Depending on the compiler version, the parentheses may be part of the attribute.
+    let attr = if attr.starts_with('(') && attr.ends_with(')') {
+        &attr[1..attr.len() - 1]
+    } else {
+        attr
+    };
+    let mut options = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in attr.char_indices() {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            // Commas within closure arguments are not followed by `name =`
+            ',' if depth == 0 && starts_with_option(&attr[i + 1..]) => {
+                options.push(split_option(&attr[start..i]));
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if !attr[start..].trim().is_empty() {
+        options.push(split_option(&attr[start..]));
+    }
+    options
+}
+
+/// Checks whether `s` starts with `name =`.
+fn starts_with_option(s: &str) -> bool {
+    let s = s.trim_start();
+    let name_len = s
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(s.len());
+    let rest = s[name_len..].trim_start();
+    name_len > 0 && rest.starts_with('=') && !rest.starts_with("==")
+}
+
+fn split_option(option: &str) -> (String, String) {
+    match option.find('=') {
+        Some(pos) => (
+            option[..pos].trim().to_string(),
+            option[pos + 1..].trim().to_string(),
+        ),
+        None => panic!("Expected `name = value`, got `{}`", option.trim()),
     }
 }
 
+fn parse_expr(expr: &str) -> Expr {
+    syn::parse(expr.parse().unwrap()).unwrap()
+}
+
 #[proc_macro_attribute]
-pub fn persistent_cache(_attr: TokenStream, func: TokenStream) -> TokenStream {
+pub fn persistent_cache(attr: TokenStream, func: TokenStream) -> TokenStream {
+    let options = parse_options(&attr.to_string());
     let func = Function::parse(func);
-    let stuff = function_persistenticator(&func);
-    stuff
+    function_persistenticator(&func, &options)
 }
 
-fn function_persistenticator(func: &Function) -> TokenStream {
+fn function_persistenticator(func: &Function, options: &[(String, String)]) -> TokenStream {
     let vis = &func.vis;
     let fn_token = &func.fn_token;
     let ident = &func.ident;
@@ -83,59 +247,200 @@ fn function_persistenticator(func: &Function) -> TokenStream {
     let output = &func.output;
     let block = &func.block;
     let attrs = &func.attrs;
-    // TODO: Deal with empty case
-    // Also, make this less horrible...
-    let tts = &attrs[0].tts[0];
-    let attr = &quote!(#tts).to_string();
-    let brackets: &[_] = &['(', ')'];
-    let quotes: &[_] = &['"', '"'];
-    let attr = attr.trim_matches(brackets);
-    let attrs: Vec<&str> = attr.split(',').map(|x| x.trim()).collect();
-    let storage: Ident = attrs[0].into();
-    let path: &str = attrs[1].trim_matches(quotes);
+
+    let mut key_fn = None;
+    let mut cache_if = None;
+    let mut named = None;
+    // All other options are methods of `CacheOptions`
+    let mut opts = vec![];
+    for (name, value) in options {
+        match (name.as_str(), value.as_str()) {
+            ("key", _) => key_fn = Some(parse_expr(value)),
+            ("cache_if", _) => cache_if = Some(parse_expr(value)),
+            ("storage", _) => named = Some(parse_expr(value)),
+            ("version", "auto") => {
+                // Any change to the signature or the body results in a different version
+                let mut h = DefaultHasher::new();
+                quote!(#inputs #output #block).to_string().hash(&mut h);
+                let version = h.finish();
+                opts.push(quote!{ .version(#version) });
+            }
+            _ => {
+                let name: Ident = name.as_str().into();
+                let value = parse_expr(value);
+                opts.push(quote!{ .#name(#value) });
+            }
+        }
+    }
+
+    // Every argument is bound to a plain variable `__pc_arg<i>`, which is hashed as a whole and
+    // destructured into the original pattern before the body is executed.
+    let mut new_inputs = vec![];
+    let mut args = vec![];
+    let mut key_args = vec![];
+    let mut bindings = vec![];
+    for (i, input) in func.inputs.iter().enumerate() {
+        match input.into_item() {
+            FnArg::Captured(ArgCaptured { pat, ty, .. }) => {
+                let arg: Ident = format!("__pc_arg{}", i).as_str().into();
+                if !func.ignored.contains(&without_whitespace(&quote!(#pat).to_string())) {
+                    key_args.push(arg);
+                }
+                new_inputs.push(quote!{ #arg: #ty });
+                bindings.push(quote!{ let #pat = #arg; });
+                args.push(arg);
+            }
+            input => new_inputs.push(quote!{ #input }),
+        }
+    }
+    let hash_inputs: Vec<_> = match key_fn {
+        // Hash the value returned by the key function...
+        Some(key_fn) => vec![quote!{
+            (&KeyArg(&KeyFn::apply_key((#(&#args,)*), #key_fn))).add_to(&mut key);
+        }],
+        // ... or all arguments which are not marked with `#[cache_ignore]`
+        None => key_args
+            .iter()
+            .map(|arg| quote!{ (&KeyArg(&#arg)).add_to(&mut key); })
+            .collect(),
+    };
+    // Whether a computed result `res` is stored according to the predicate `cache_if`
+    let accepted = match cache_if {
+        Some(cache_if) => quote!{ options::accepts(&res, #cache_if) },
+        None => quote!{ true },
+    };
+    // The `Debug` representation of the arguments which are part of the key
+    let debug_args: Vec<_> = key_args
+        .iter()
+        .map(|arg| quote!{ (&DebugArg(&#arg)).debug_repr() })
+        .collect();
+    let (storage_decl, storage_type) = match named {
+        // The storage registered under the given name...
+        Some(name) => (
+            quote!{
+                static ref S: registry::SharedStorage = registry::storage(#name).unwrap();
+            },
+            quote!{ Box<dyn PersistentCache + Send> },
+        ),
+        // ... or one created from `#[params(...)]`
+        None => {
+            // TODO: Deal with empty case
+            // Also, make this less horrible...
+            let tts = &attrs[0].tts[0];
+            let attr = &quote!(#tts).to_string();
+            let quotes: &[_] = &['"', '"'];
+            let attr = if attr.starts_with('(') && attr.ends_with(')') {
+                &attr[1..attr.len() - 1]
+            } else {
+                attr
+            };
+            // The location may contain commas itself
+            let attrs: Vec<&str> = attr.splitn(2, ',').map(|x| x.trim()).collect();
+            let storage: Ident = attrs[0].into();
+            // The location is either a string literal, an expression evaluated on the first call
+            // or missing, in which case the configured one is used
+            let new_storage = match attrs.get(1) {
+                Some(path) if path.starts_with('"') && path.ends_with('"') => {
+                    let path: &str = path.trim_matches(quotes);
+                    quote!{ #storage::new(#path) }
+                }
+                Some(path) => {
+                    let path = parse_expr(path);
+                    quote!{ #storage::new(&#path) }
+                }
+                None => quote!{ #storage::from_config() },
+            };
+            (
+                quote!{
+                    static ref S: ::std::sync::Mutex<#storage> = ::std::sync::Mutex::new(#new_storage.unwrap());
+                },
+                quote!{ #storage },
+            )
+        }
+    };
 
     let pers_func = quote!{
-        #vis #fn_token #ident(#inputs) #output
+        #vis #fn_token #ident(#(#new_inputs),*) #output
         {
-            extern crate bincode;
-            use std::hash::{Hash, Hasher};
             lazy_static!{
-                static ref S: ::std::sync::Mutex<#storage> = ::std::sync::Mutex::new(#storage::new(#path).unwrap());
+                #storage_decl
             };
-            let mut s = ::std::collections::hash_map::DefaultHasher::new();
-
+            let mode = mode::mode();
+            let mut rec = stats::Recorder::with_storage_type(
+                stringify!(#ident),
+                ::std::any::type_name::<#storage_type>(),
+            );
+            // With caching off, the storage is not even created
+            let lookup = if mode == Mode::Off {
+                None
+            } else {
+                let opts = CacheOptions::new()#(#opts)*;
+                let mut key = KeyBuilder::new();
+                #(#hash_inputs)*
+                key.end_args();
+                opts.add_to_key(&mut key);
+                let codec = opts.codec_for(S.lock().unwrap().codec());
+                codec.add_to_key(&mut key);
 
-            macro_rules! expand_inputs {
-                ($s:ident;) => {};
-                ($s:ident; $var:ident : $type:ty) => {
-                    $var.hash(&mut $s);
+                let var_name = key.name("fu", stringify!(#ident));
+                let result: Vec<u8> = if mode.reads() {
+                    rec.storage(|| S.lock().unwrap().get(&var_name))
+                } else {
+                    vec![]
                 };
-                ($s:ident; $var:ident : $type:ty, $($x:ident : $y:ty,)*) => {
-                    expand_inputs!($s; $var : $type);
-                    expand_inputs!($s; $($x : $y),*);
-                };
-            }
-
-            expand_inputs!(s; #inputs,);
-
-            let var_name = format!("{}_{}_{}_{:?}", PREFIX, "fu", stringify!(#ident), s.finish());
-            let result: Vec<u8> = S.lock().unwrap().get(&var_name).unwrap();
-            match result.len() {
-                0 => {
-                    // Computing and storing the value
-                    let res = #block;
-                    S.lock().unwrap().set(&var_name, &bincode::serialize(&res).unwrap()).unwrap();
-                    return res;
-                },
-                _ => {
+                if let Some(res) = entry::decode(codec, opts.schema_version(), &result) {
                     // Fetching the value
-                    return bincode::deserialize(&result).unwrap()
-                },
+                    rec.hit(&var_name, result.len());
+                    return res;
+                }
+                Some((opts, codec, var_name))
+            };
+
+            // Computing and storing the value
+            let debug_args = match lookup {
+                Some((ref opts, _, _)) if opts.records_args() => {
+                    let args: Vec<String> = vec![#(#debug_args),*];
+                    Some(args.join(", "))
+                }
+                _ => None,
             };
+            let res = rec.compute(|| {
+                #(#bindings)*
+                __pc_body
+            });
+            match lookup {
+                Some((opts, codec, var_name)) => {
+                    let entry = entry::encode(codec, opts.schema_version(), &rec.metadata(debug_args), &res).unwrap();
+                    if mode.writes() && #accepted && opts.stores(rec.last_compute_secs(), entry.len()) {
+                        rec.storage(|| S.lock().unwrap().set(&var_name, &entry));
+                        rec.miss(&var_name, entry.len());
+                    } else {
+                        rec.skip(&var_name, entry.len());
+                    }
+                }
+                None => rec.bypass(),
+            }
+            res
         }
     };
-    // bypass #46489 (Proc macro hygiene regression)
-    let pers_func = pers_func.to_string().parse().unwrap();
-    pers_func
-    // pers_func.into()
+    insert_body(pers_func.into(), &func.body)
+}
+
+/// Replaces the placeholder `__pc_body` in `tokens` by the statements of the body. The code
+/// generated with `quote` loses all spans, since the `proc-macro2` version used by `syn` cannot
+/// keep them, but errors in the body should still point to the body. The braces of the body are
+/// left out, otherwise rustc suggests removing them for bodies consisting of one expression.
+fn insert_body(tokens: TokenStream, body: &TokenStream) -> TokenStream {
+    tokens
+        .into_iter()
+        .flat_map(|token| match token {
+            TokenTree::Ident(ref ident) if ident.to_string() == "__pc_body" => body.clone(),
+            TokenTree::Group(ref group) => {
+                let mut new = Group::new(group.delimiter(), insert_body(group.stream(), body));
+                new.set_span(group.span());
+                TokenStream::from(TokenTree::Group(new))
+            }
+            token => TokenStream::from(token),
+        })
+        .collect()
 }
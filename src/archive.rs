@@ -0,0 +1,288 @@
+// Copyright 2018 Stefan Kroboth
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # Archives
+//!
+//! Moves cached results between storages, e.g. from a `RedisStorage` on a cluster to a
+//! `FileStorage` on a laptop. `export` writes all entries of a storage into a single archive file,
+//! `import` stores the entries of an archive in a storage and `copy_between` copies the entries of
+//! one storage to another one directly. Only storages which can enumerate their entries (see
+//! `PersistentCache::keys`) can be exported.
+//!
+//! # Format
+//!
+//! All integers are little endian.
+//!
+//! 1. Header: the magic bytes `PCARCHIV` followed by the format version as `u32` (currently 1).
+//! 2. The stored values, one after another.
+//! 3. Index: the number of entries as `u64`, followed by, for every entry, the length of its name
+//!    as `u32`, the name, its offset and length as `u64`, the time it was last written as
+//!    seconds and nanoseconds since the Unix epoch (`u64` and `u32`, both 0 if unknown) and its
+//!    remaining time to live in milliseconds as `u64` (0 if it does not expire).
+//! 4. Footer: the offset of the index as `u64` followed by the magic bytes.
+//!
+//! The entries themselves are stored unchanged, including their fingerprint and metadata (see
+//! `entry`), hence the codec and compression of the storages have to match. Entries which expire
+//! in the exporting storage (see `PersistentCache::ttl`) expire after the same time in the
+//! importing one, if it lets variables expire. The times the storage reports as modification
+//! times are recorded in the index as well, but storages do not allow to set them, therefore
+//! imported entries count as written at the time of the import.
+//!
+//! Archives are checked against their length before anything is allocated, hence corrupt or
+//! malicious archives result in an error.
+//!
+//! # Example
+//!
+//! ```
+//! use persistentcache::archive::{export, import};
+//! use persistentcache::storage::file::FileStorage;
+//! use persistentcache::storage::memory::MemoryStorage;
+//! use std::io::Cursor;
+//!
+//! # let dir = std::env::temp_dir().join("persistentcache_example");
+//! # let dir = dir.to_str().unwrap();
+//! let mut src = FileStorage::new(dir).unwrap();
+//! let mut archive = Cursor::new(vec![]);
+//! export(&mut src, &mut archive).unwrap();
+//!
+//! let mut dst = MemoryStorage::new();
+//! archive.set_position(0);
+//! import(&mut dst, &mut archive).unwrap();
+//! ```
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use errors::*;
+use PersistentCache;
+
+/// Magic bytes at the start and the end of every archive
+const MAGIC: &[u8; 8] = b"PCARCHIV";
+/// Version of the archive format
+const VERSION: u32 = 1;
+/// Length of the header
+const HEADER_LEN: u64 = 12;
+/// Length of the footer
+const FOOTER_LEN: u64 = 16;
+/// Number of values fetched from or stored in a storage at once
+const BATCH: usize = 100;
+
+/// Entry of the index of an archive
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    /// Name of the variable
+    pub name: String,
+    /// Time the value was last written, if the exporting storage knew it
+    pub modified: Option<SystemTime>,
+    /// Remaining time to live, if the value expires in the exporting storage
+    pub ttl: Option<Duration>,
+    /// Length of the value in bytes
+    pub len: u64,
+    /// Offset of the value in the archive
+    offset: u64,
+}
+
+/// Writes all entries of `storage` to `writer`. Returns the number of exported entries.
+pub fn export<S, W>(storage: &mut S, mut writer: W) -> Result<usize>
+where
+    S: PersistentCache + ?Sized,
+    W: Write,
+{
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    let mut offset = HEADER_LEN;
+
+    let keys = storage.keys()?;
+    let mut index = Vec::with_capacity(keys.len());
+    for chunk in keys.chunks(BATCH) {
+        let names: Vec<&str> = chunk.iter().map(|name| name.as_str()).collect();
+        let vals = storage.get_many(&names)?;
+        for (name, val) in chunk.iter().zip(vals) {
+            // The entry may have been deleted in the meantime
+            if val.is_empty() {
+                continue;
+            }
+            writer.write_all(&val)?;
+            index.push(ArchiveEntry {
+                name: name.clone(),
+                modified: storage.modified(name)?,
+                ttl: storage.ttl(name)?,
+                len: val.len() as u64,
+                offset,
+            });
+            offset += val.len() as u64;
+        }
+    }
+
+    writer.write_all(&(index.len() as u64).to_le_bytes())?;
+    for entry in &index {
+        writer.write_all(&(entry.name.len() as u32).to_le_bytes())?;
+        writer.write_all(entry.name.as_bytes())?;
+        writer.write_all(&entry.offset.to_le_bytes())?;
+        writer.write_all(&entry.len.to_le_bytes())?;
+        let since_epoch = entry
+            .modified
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        writer.write_all(&since_epoch.as_secs().to_le_bytes())?;
+        writer.write_all(&since_epoch.subsec_nanos().to_le_bytes())?;
+        let ttl = entry.ttl.map_or(0, |ttl| ttl.as_secs() * 1000 + u64::from(ttl.subsec_millis()));
+        writer.write_all(&ttl.to_le_bytes())?;
+    }
+    writer.write_all(&offset.to_le_bytes())?;
+    writer.write_all(MAGIC)?;
+    writer.flush()?;
+    Ok(index.len())
+}
+
+/// Reads the index of the archive `reader`.
+pub fn index<R: Read + Seek>(reader: &mut R) -> Result<Vec<ArchiveEntry>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    if len < HEADER_LEN + FOOTER_LEN {
+        bail!("Archive is truncated");
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("Not a persistentcache archive");
+    }
+    let version = read_u32(reader)?;
+    if version != VERSION {
+        bail!("Unsupported archive version {}", version);
+    }
+
+    let index_end = len - FOOTER_LEN;
+    reader.seek(SeekFrom::Start(index_end))?;
+    let index_offset = read_u64(reader)?;
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("Archive is truncated");
+    }
+    if index_offset < HEADER_LEN || index_offset > index_end - 8 {
+        bail!("Archive is corrupt: index out of bounds");
+    }
+
+    reader.seek(SeekFrom::Start(index_offset))?;
+    let count = read_u64(reader)?;
+    // Length of an entry of the index without its name
+    let fixed_len = 40;
+    let mut remaining = index_end - index_offset - 8;
+    if count > remaining / fixed_len {
+        bail!("Archive is corrupt: too many entries");
+    }
+    let mut index = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let name_len = u64::from(read_u32(reader)?);
+        if name_len + fixed_len > remaining {
+            bail!("Archive is corrupt: index out of bounds");
+        }
+        remaining -= name_len + fixed_len;
+        let mut name = vec![0; name_len as usize];
+        reader.read_exact(&mut name)?;
+        let name = match String::from_utf8(name) {
+            Ok(name) => name,
+            Err(_) => bail!("Archive contains an invalid name"),
+        };
+        let offset = read_u64(reader)?;
+        let len = read_u64(reader)?;
+        if offset < HEADER_LEN || offset > index_offset || len > index_offset - offset {
+            bail!("Archive is corrupt: value of `{}` out of bounds", name);
+        }
+        let secs = read_u64(reader)?;
+        let nanos = read_u32(reader)?;
+        let modified = if secs == 0 && nanos == 0 {
+            None
+        } else {
+            Some(UNIX_EPOCH + Duration::new(secs, nanos))
+        };
+        let ttl = read_u64(reader)?;
+        index.push(ArchiveEntry {
+            name,
+            modified,
+            ttl: if ttl == 0 {
+                None
+            } else {
+                Some(Duration::from_millis(ttl))
+            },
+            len,
+            offset,
+        });
+    }
+    Ok(index)
+}
+
+/// Stores all entries of the archive `reader` in `storage`, overwriting entries of the same name.
+/// Returns the number of imported entries.
+pub fn import<S, R>(storage: &mut S, mut reader: R) -> Result<usize>
+where
+    S: PersistentCache + ?Sized,
+    R: Read + Seek,
+{
+    let index = index(&mut reader)?;
+    for chunk in index.chunks(BATCH) {
+        let mut vals = Vec::with_capacity(chunk.len());
+        for entry in chunk {
+            reader.seek(SeekFrom::Start(entry.offset))?;
+            let mut val = vec![0; entry.len as usize];
+            reader.read_exact(&mut val)?;
+            vals.push(val);
+        }
+        let entries: Vec<(&str, &[u8])> = chunk
+            .iter()
+            .zip(&vals)
+            .map(|(entry, val)| (entry.name.as_str(), &val[..]))
+            .collect();
+        storage.set_many(&entries)?;
+        for entry in chunk {
+            if let Some(ttl) = entry.ttl {
+                storage.set_ttl(&entry.name, ttl)?;
+            }
+        }
+    }
+    Ok(index.len())
+}
+
+/// Copies all entries of `src` to `dst`, overwriting entries of the same name. Returns the number
+/// of copied entries.
+pub fn copy_between<S, D>(src: &mut S, dst: &mut D) -> Result<usize>
+where
+    S: PersistentCache + ?Sized,
+    D: PersistentCache + ?Sized,
+{
+    let keys = src.keys()?;
+    let mut copied = 0;
+    for chunk in keys.chunks(BATCH) {
+        let names: Vec<&str> = chunk.iter().map(|name| name.as_str()).collect();
+        let vals = src.get_many(&names)?;
+        let entries: Vec<(&str, &[u8])> = names
+            .iter()
+            .zip(&vals)
+            .filter(|&(_, val)| !val.is_empty())
+            .map(|(&name, val)| (name, &val[..]))
+            .collect();
+        dst.set_many(&entries)?;
+        for &(name, _) in &entries {
+            if let Some(ttl) = src.ttl(name)? {
+                dst.set_ttl(name, ttl)?;
+            }
+        }
+        copied += entries.len();
+    }
+    Ok(copied)
+}
+
+fn read_u32<R: Read + ?Sized>(reader: &mut R) -> Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read + ?Sized>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
@@ -0,0 +1,143 @@
+// Copyright 2018 Stefan Kroboth
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # Codecs
+//!
+//! Formats in which cached values are serialized. `Bincode` is always available and used by
+//! default. The other formats need to be enabled via the features `json`, `msgpack` and `cbor`.
+//! JSON, MessagePack and CBOR can be read from other languages and tolerate fields being added to
+//! structs (if the new fields are marked with `#[serde(default)]`).
+//!
+//! The codec can be chosen per storage via `with_codec` or per function via the option
+//! `codec = ...`, which takes precedence. A value stored with one codec is never read with
+//! another one, because the codec is part of the cache key unless it is `Bincode`. The codec is
+//! recorded in every stored entry as well, see `entry`.
+//!
+//! # Example
+//!
+//! ```
+//! use persistentcache::codec::Codec;
+//!
+//! let bytes = Codec::Bincode.encode(&vec![1, 2, 3]).unwrap();
+//! let value: Vec<i32> = Codec::Bincode.decode(&bytes).unwrap();
+//! assert_eq!(value, vec![1, 2, 3]);
+//! ```
+use bincode;
+#[cfg(feature = "msgpack")]
+use rmp_serde;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+#[cfg(feature = "cbor")]
+use serde_cbor;
+#[cfg(feature = "json")]
+use serde_json;
+use std::hash::{Hash, Hasher};
+
+use errors::*;
+
+/// Format in which cached values are stored
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Codec {
+    /// Compact binary format of the crate `bincode`
+    #[default]
+    Bincode,
+    /// JSON via `serde_json`
+    #[cfg(feature = "json")]
+    Json,
+    /// MessagePack via `rmp-serde`. Structs are stored as maps with field names.
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    /// CBOR via `serde_cbor`
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Codec {
+    /// Returns the name of the codec.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Codec::Bincode => "bincode",
+            #[cfg(feature = "json")]
+            Codec::Json => "json",
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => "msgpack",
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => "cbor",
+        }
+    }
+
+    /// Returns the byte identifying the codec in stored entries.
+    pub fn id(&self) -> u8 {
+        match *self {
+            Codec::Bincode => 0,
+            #[cfg(feature = "json")]
+            Codec::Json => 1,
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => 2,
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => 3,
+        }
+    }
+
+    /// Returns the codec identified by `id`, `None` if it is unknown or its feature is disabled.
+    pub fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            0 => Some(Codec::Bincode),
+            #[cfg(feature = "json")]
+            1 => Some(Codec::Json),
+            #[cfg(feature = "msgpack")]
+            2 => Some(Codec::MessagePack),
+            #[cfg(feature = "cbor")]
+            3 => Some(Codec::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Serializes `value`.
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match *self {
+            Codec::Bincode => {
+                bincode::serialize(value).chain_err(|| "Failed to encode with bincode")
+            }
+            #[cfg(feature = "json")]
+            Codec::Json => serde_json::to_vec(value).chain_err(|| "Failed to encode as JSON"),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => {
+                rmp_serde::to_vec_named(value).chain_err(|| "Failed to encode as MessagePack")
+            }
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => serde_cbor::to_vec(value).chain_err(|| "Failed to encode as CBOR"),
+        }
+    }
+
+    /// Deserializes a value from `bytes`.
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        match *self {
+            Codec::Bincode => {
+                bincode::deserialize(bytes).chain_err(|| "Failed to decode with bincode")
+            }
+            #[cfg(feature = "json")]
+            Codec::Json => serde_json::from_slice(bytes).chain_err(|| "Failed to decode JSON"),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => {
+                rmp_serde::from_slice(bytes).chain_err(|| "Failed to decode MessagePack")
+            }
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => serde_cbor::from_slice(bytes).chain_err(|| "Failed to decode CBOR"),
+        }
+    }
+
+    /// Feeds the codec into the cache key. Nothing is added for `Bincode`, such that keys stay the
+    /// same as before codecs were introduced.
+    #[doc(hidden)]
+    pub fn add_to_key<H: Hasher>(&self, state: &mut H) {
+        if *self != Codec::Bincode {
+            self.name().hash(state);
+        }
+    }
+}
@@ -0,0 +1,121 @@
+// Copyright 2018 Stefan Kroboth
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # Configuration
+//!
+//! Storages created without an explicit location, e.g. with `FileStorage::from_config()`,
+//! `cache_func!(File, fn ...)` or `#[params(FileStorage)]`, take their location from, in this
+//! order:
+//!
+//! 1. the environment variables `PERSISTENTCACHE_DIR` and `PERSISTENTCACHE_REDIS_URL`,
+//! 2. the config file named by the environment variable `PERSISTENTCACHE_CONFIG`, or
+//!    `persistentcache.conf` in the working directory if it exists,
+//! 3. the defaults `.persistentcache` and `redis://127.0.0.1`.
+//!
+//! The config file consists of lines `name = value`, empty lines and comments starting with `#`.
+//! Values may be quoted.
+//!
+//! ```text
+//! # Cache shared by all nodes of the cluster
+//! dir = "/scratch/cache"
+//! redis_url = redis://cache.cluster.local
+//! ```
+//!
+//! The config file is read once per process.
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use errors::*;
+use warn;
+
+/// Environment variable holding the directory of file based storages
+pub const DIR_VAR: &str = "PERSISTENTCACHE_DIR";
+/// Environment variable holding the URL of Redis storages
+pub const REDIS_URL_VAR: &str = "PERSISTENTCACHE_REDIS_URL";
+/// Environment variable holding the path of the config file
+pub const CONFIG_VAR: &str = "PERSISTENTCACHE_CONFIG";
+/// Config file which is read if `PERSISTENTCACHE_CONFIG` is not set
+pub const CONFIG_FILE: &str = "persistentcache.conf";
+/// Directory of file based storages if none is configured
+pub const DEFAULT_DIR: &str = ".persistentcache";
+/// URL of Redis storages if none is configured
+pub const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1";
+
+lazy_static! {
+    static ref CONFIG: Config = Config::from_env().unwrap_or_else(|e| {
+        warn(&format!("{}, using the default configuration", e));
+        Config::default()
+    });
+}
+
+/// Contents of a config file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    /// Directory of file based storages
+    pub dir: Option<String>,
+    /// URL of Redis storages
+    pub redis_url: Option<String>,
+}
+
+impl Config {
+    /// Parses the contents of a config file.
+    pub fn parse(contents: &str) -> Result<Config> {
+        let mut config = Config::default();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = match line.find('=') {
+                Some(pos) => (line[..pos].trim(), line[pos + 1..].trim()),
+                None => bail!("Expected `name = value` in line {} of the config", i + 1),
+            };
+            let value = value.trim_matches('"').to_string();
+            match name {
+                "dir" => config.dir = Some(value),
+                "redis_url" => config.redis_url = Some(value),
+                _ => bail!("Unknown option `{}` in line {} of the config", name, i + 1),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Reads the config file `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        Config::parse(&contents)
+    }
+
+    /// Reads the config file named by `PERSISTENTCACHE_CONFIG` or `persistentcache.conf` if it
+    /// exists.
+    pub fn from_env() -> Result<Config> {
+        match env::var(CONFIG_VAR) {
+            Ok(path) => Config::load(path),
+            Err(_) if Path::new(CONFIG_FILE).is_file() => Config::load(CONFIG_FILE),
+            Err(_) => Ok(Config::default()),
+        }
+    }
+}
+
+/// Returns the configured directory of file based storages.
+pub fn dir() -> String {
+    env::var(DIR_VAR)
+        .ok()
+        .or_else(|| CONFIG.dir.clone())
+        .unwrap_or_else(|| DEFAULT_DIR.to_string())
+}
+
+/// Returns the configured URL of Redis storages.
+pub fn redis_url() -> String {
+    env::var(REDIS_URL_VAR)
+        .ok()
+        .or_else(|| CONFIG.redis_url.clone())
+        .unwrap_or_else(|| DEFAULT_REDIS_URL.to_string())
+}
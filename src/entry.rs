@@ -0,0 +1,207 @@
+// Copyright 2018 Stefan Kroboth
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # Entries
+//!
+//! Every stored value is preceded by a fingerprint of its type and by metadata:
+//!
+//! 1. The fingerprint: a hash of the type name as returned by `std::any::type_name`, the schema
+//!    version (option `schema = ...`, 0 by default) and the version of this format as little
+//!    endian `u64`.
+//! 2. The codec of the value as one byte, see `Codec::id`.
+//! 3. The length of the metadata as little endian `u32`, followed by the `Metadata` encoded with
+//!    bincode, regardless of the codec of the value.
+//! 4. The value, encoded with the codec.
+//!
+//! When a value is fetched, a fingerprint or codec which does not match the expected one, as well
+//! as a value which cannot be decoded, is treated as if nothing was stored. The value is computed
+//! again and overwritten. Bump the schema version whenever the serialized form of the return type
+//! changes without its name changing, e.g. when a field is added to a struct.
+//!
+//! Like the cache key, the fingerprint may change with a new version of the compiler or when the
+//! type is moved, see [Stability](../key/index.html#stability).
+//!
+//! # Example
+//!
+//! ```
+//! use persistentcache::storage::file::FileStorage;
+//! use persistentcache::PersistentCache;
+//!
+//! # let dir = std::env::temp_dir().join("persistentcache_example");
+//! # let dir = dir.to_str().unwrap();
+//! let mut s = FileStorage::new(dir).unwrap();
+//! for name in s.keys().unwrap() {
+//!     if let Some(meta) = s.metadata(&name).unwrap() {
+//!         println!("{}: computed in {} s", meta.function, meta.compute_secs);
+//!     }
+//! }
+//! ```
+use bincode;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::any::type_name;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::time::SystemTime;
+
+use errors::*;
+use Codec;
+
+/// Length of the fingerprint preceding every value
+pub const FINGERPRINT_LEN: usize = 8;
+/// Version of the format, part of the fingerprint such that entries of older versions are ignored
+const FORMAT_VERSION: u64 = 2;
+
+/// Metadata stored with every value
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Name of the cached function
+    pub function: String,
+    /// Time the value was computed
+    pub created: SystemTime,
+    /// Seconds spent computing the value
+    pub compute_secs: f64,
+    /// `Debug` representation of the arguments which are part of the key, if recorded (option
+    /// `record_args = true`). Arguments which do not implement `Debug` are shown as `_`.
+    pub args: Option<String>,
+    /// Size of the stored entry in bytes (not stored, filled in when the entry is read)
+    #[serde(skip)]
+    pub size: u64,
+    /// Time the entry was last read, if the storage keeps track of it (not stored)
+    #[serde(skip)]
+    pub accessed: Option<SystemTime>,
+}
+
+impl Metadata {
+    /// Returns the metadata of a value of `function` computed just now in `compute_secs` from the
+    /// arguments `args`.
+    pub fn new(function: &str, compute_secs: f64, args: Option<String>) -> Self {
+        Metadata {
+            function: function.to_string(),
+            created: SystemTime::now(),
+            compute_secs,
+            args,
+            size: 0,
+            accessed: None,
+        }
+    }
+}
+
+/// Returns the fingerprint of type `T` with schema version `schema`.
+pub fn fingerprint<T: ?Sized>(schema: u64) -> u64 {
+    let mut h = DefaultHasher::new();
+    type_name::<T>().hash(&mut h);
+    schema.hash(&mut h);
+    FORMAT_VERSION.hash(&mut h);
+    h.finish()
+}
+
+/// Encodes `value` with `codec` and prepends its fingerprint and `meta`.
+pub fn encode<T: Serialize>(
+    codec: Codec,
+    schema: u64,
+    meta: &Metadata,
+    value: &T,
+) -> Result<Vec<u8>> {
+    let meta = bincode::serialize(meta).chain_err(|| "Failed to encode metadata")?;
+    let mut out = fingerprint::<T>(schema).to_le_bytes().to_vec();
+    out.push(codec.id());
+    out.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+    out.extend(meta);
+    out.extend(codec.encode(value)?);
+    Ok(out)
+}
+
+/// Splits a value stored by `encode` into the fingerprint, the codec, the encoded metadata and
+/// the encoded value. Returns `None` if it is too short.
+fn parts(bytes: &[u8]) -> Option<(u64, u8, &[u8], &[u8])> {
+    if bytes.len() < FINGERPRINT_LEN + 5 {
+        return None;
+    }
+    let mut fingerprint = [0; FINGERPRINT_LEN];
+    fingerprint.copy_from_slice(&bytes[..FINGERPRINT_LEN]);
+    let codec = bytes[FINGERPRINT_LEN];
+    let mut len = [0; 4];
+    len.copy_from_slice(&bytes[FINGERPRINT_LEN + 1..FINGERPRINT_LEN + 5]);
+    let rest = &bytes[FINGERPRINT_LEN + 5..];
+    let len = u32::from_le_bytes(len) as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let (meta, value) = rest.split_at(len);
+    Some((u64::from_le_bytes(fingerprint), codec, meta, value))
+}
+
+/// Splits a value stored by `encode` into the fingerprint, the id of its codec (see `Codec::id`)
+/// and the encoded value. Returns `None` if it is too short to contain a fingerprint and metadata.
+///
+/// # Example
+///
+/// ```
+/// use persistentcache::entry::{encode, split};
+/// use persistentcache::{Codec, Metadata};
+///
+/// let bytes = encode(Codec::Bincode, 0, &Metadata::new("f", 0.1, None), &42_u8).unwrap();
+/// let (_, codec, value) = split(&bytes).unwrap();
+/// assert_eq!(Codec::from_id(codec), Some(Codec::Bincode));
+/// assert_eq!(value, &[42]);
+/// ```
+pub fn split(bytes: &[u8]) -> Option<(u64, u8, &[u8])> {
+    parts(bytes).map(|(fingerprint, codec, _, value)| (fingerprint, codec, value))
+}
+
+/// Returns the metadata of a value stored by `encode`, or `None` if it has none.
+pub fn metadata(bytes: &[u8]) -> Option<Metadata> {
+    let (_, _, meta, _) = parts(bytes)?;
+    let mut meta: Metadata = bincode::deserialize(meta).ok()?;
+    meta.size = bytes.len() as u64;
+    Some(meta)
+}
+
+/// Decodes a value stored by `encode`. Returns `None` if the fingerprint or the codec does not
+/// match or if the value cannot be decoded.
+pub fn decode<T: DeserializeOwned>(codec: Codec, schema: u64, bytes: &[u8]) -> Option<T> {
+    match parts(bytes) {
+        Some((stored, id, _, value)) if stored == fingerprint::<T>(schema) && id == codec.id() => {
+            codec.decode(value).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Wrapper around an argument which is used by the macros to record its `Debug` representation if
+/// it implements `Debug` and `_` otherwise.
+#[doc(hidden)]
+pub struct DebugArg<'a, T: 'a + ?Sized>(pub &'a T);
+
+/// Record a `DebugArg` via `Debug`.
+#[doc(hidden)]
+pub trait ViaDebug {
+    /// Returns the representation of the wrapped argument.
+    fn debug_repr(&self) -> String;
+}
+
+impl<'a, T: Debug + ?Sized> ViaDebug for DebugArg<'a, T> {
+    fn debug_repr(&self) -> String {
+        format!("{:?}", self.0)
+    }
+}
+
+/// Record a `DebugArg` as `_`. This is only picked by method resolution if `ViaDebug` does not
+/// apply because it is implemented on a reference.
+#[doc(hidden)]
+pub trait ViaNoDebug {
+    /// Returns the representation of the wrapped argument.
+    fn debug_repr(&self) -> String;
+}
+
+impl<'a, 'b, T: ?Sized> ViaNoDebug for &'b DebugArg<'a, T> {
+    fn debug_repr(&self) -> String {
+        "_".to_string()
+    }
+}
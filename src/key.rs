@@ -0,0 +1,404 @@
+// Copyright 2018 Stefan Kroboth
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # Cache keys
+//!
+//! The arguments of a cached function are hashed in order to obtain the name under which the
+//! result is stored. Every type implementing `Hash` can be used as an argument. Types which do not
+//! implement `Hash` (such as floats) or which should be hashed differently can implement the
+//! `CacheKey` trait instead. If a type implements both traits, `CacheKey` takes precedence.
+//!
+//! `CacheKey` is implemented for the primitive types, `f32`, `f64` and for slices, vectors,
+//! options, boxes, references and tuples of types implementing `CacheKey`. Hence containers of
+//! floats such as `Vec<f64>` or `&[f32]` can be used as arguments directly.
+//!
+//! # Encoding
+//!
+//! The name under which a result is stored has the form `PREFIX_prefix_function_hash`, where
+//! `function` is the name of the function with `::` replaced by `.` and `hash` is computed with
+//! `std::collections::hash_map::DefaultHasher` from, in this order:
+//!
+//! 1. for every argument which is part of the key, its type name as returned by
+//!    `std::any::type_name` followed by its value (via `CacheKey` or `Hash`). If a key function is
+//!    used, its return value takes the place of the arguments.
+//! 2. the number of values added in 1. as `u64`.
+//! 3. the options which are part of the key, such as the version.
+//!
+//! This is the same for `cache!`, `cache_func!` and `#[persistent_cache]`. Since the type is part
+//! of the key, `f(1_i64)` and `f(1_u64)` are cached separately.
+//!
+//! # Stability
+//!
+//! Neither the output of `std::any::type_name` nor the algorithm of `DefaultHasher` is guaranteed
+//! to stay the same. A new version of the compiler, or moving or renaming the type of an argument,
+//! may therefore change the names under which results are stored. Results cached before are not
+//! found anymore and are computed again. They stay in the storage until it is flushed or they are
+//! deleted, e.g. with `persistentcache <storage> delete --older-than 30d`. Types implementing
+//! `CacheKey` are subject to this as well, since their type name is part of the key, too. The same
+//! applies to the fingerprint of the return type stored with every value, see the `entry` module:
+//! values whose fingerprint changed are treated as if nothing was stored.
+//!
+//! # Example
+//!
+//! ```
+//! use persistentcache::key::CacheKey;
+//! use std::hash::{Hash, Hasher};
+//!
+//! struct Matrix {
+//!     rows: usize,
+//!     data: Vec<f64>,
+//! }
+//!
+//! impl CacheKey for Matrix {
+//!     fn cache_key<H: Hasher>(&self, state: &mut H) {
+//!         self.rows.hash(state);
+//!         self.data.cache_key(state);
+//!     }
+//! }
+//! ```
+use std::any::type_name;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use PREFIX;
+
+/// Characters which are not allowed in the names of stored results since they are not allowed in
+/// file names on some systems, besides `:` and control characters
+const INVALID_CHARS: &[u8] = b"/\\*?\"<>|";
+
+/// Types which can be hashed into a cache key.
+pub trait CacheKey {
+    /// Feed this value into the given `Hasher`.
+    fn cache_key<H: Hasher>(&self, state: &mut H);
+}
+
+macro_rules! cache_key_via_hash {
+    ($($t:ty),*) => {
+        $(
+            impl CacheKey for $t {
+                fn cache_key<H: Hasher>(&self, state: &mut H) {
+                    self.hash(state);
+                }
+            }
+        )*
+    };
+}
+
+cache_key_via_hash!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, bool, char, str, String, ()
+);
+
+macro_rules! cache_key_float {
+    ($($t:ident),*) => {
+        $(
+            /// `-0.0` and `0.0` as well as all NaNs are considered equal.
+            impl CacheKey for $t {
+                fn cache_key<H: Hasher>(&self, state: &mut H) {
+                    if self.is_nan() {
+                        $t::NAN.to_bits().hash(state);
+                    } else if *self == 0.0 {
+                        (0.0 as $t).to_bits().hash(state);
+                    } else {
+                        self.to_bits().hash(state);
+                    }
+                }
+            }
+        )*
+    };
+}
+
+cache_key_float!(f32, f64);
+
+impl<T: CacheKey> CacheKey for [T] {
+    fn cache_key<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+        for item in self {
+            item.cache_key(state);
+        }
+    }
+}
+
+impl<T: CacheKey> CacheKey for Vec<T> {
+    fn cache_key<H: Hasher>(&self, state: &mut H) {
+        self[..].cache_key(state);
+    }
+}
+
+impl<T: CacheKey> CacheKey for Option<T> {
+    fn cache_key<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            None => 0_u8.hash(state),
+            Some(ref x) => {
+                1_u8.hash(state);
+                x.cache_key(state);
+            }
+        }
+    }
+}
+
+impl<T: CacheKey + ?Sized> CacheKey for Box<T> {
+    fn cache_key<H: Hasher>(&self, state: &mut H) {
+        (**self).cache_key(state);
+    }
+}
+
+impl<T: CacheKey + ?Sized> CacheKey for &T {
+    fn cache_key<H: Hasher>(&self, state: &mut H) {
+        (**self).cache_key(state);
+    }
+}
+
+impl<T: CacheKey + ?Sized> CacheKey for &mut T {
+    fn cache_key<H: Hasher>(&self, state: &mut H) {
+        (**self).cache_key(state);
+    }
+}
+
+macro_rules! cache_key_tuple {
+    ($(($($t:ident . $i:tt),*)),*) => {
+        $(
+            impl<$($t: CacheKey),*> CacheKey for ($($t,)*) {
+                fn cache_key<H: Hasher>(&self, state: &mut H) {
+                    $(self.$i.cache_key(state);)*
+                }
+            }
+        )*
+    };
+}
+
+cache_key_tuple!(
+    (A.0),
+    (A.0, B.1),
+    (A.0, B.1, C.2),
+    (A.0, B.1, C.2, D.3),
+    (A.0, B.1, C.2, D.3, E.4),
+    (A.0, B.1, C.2, D.3, E.4, F.5)
+);
+
+/// Computes the name under which a result is stored, see the module documentation for details.
+pub struct KeyBuilder {
+    state: DefaultHasher,
+    args: u64,
+}
+
+impl KeyBuilder {
+    /// Returns an empty `KeyBuilder`.
+    pub fn new() -> Self {
+        KeyBuilder {
+            state: DefaultHasher::new(),
+            args: 0,
+        }
+    }
+
+    /// Adds an argument of type `T`. The closure `add` feeds the value of the argument into the
+    /// hasher.
+    pub fn arg<T: ?Sized, F: FnOnce(&mut DefaultHasher)>(&mut self, add: F) {
+        type_name::<T>().hash(&mut self.state);
+        add(&mut self.state);
+        self.args += 1;
+    }
+
+    /// Adds the number of arguments to the hash. This needs to be called after all arguments were
+    /// added and before any options are added.
+    pub fn end_args(&mut self) {
+        self.args.hash(&mut self.state);
+    }
+
+    /// Returns the name under which the result of `function` with prefix `prefix` is stored.
+    ///
+    /// Since storages may use the name as a file name, the `::` of paths is replaced by `.`.
+    /// Panics if `prefix` or `function` is not valid, see `check_name`.
+    pub fn name(&self, prefix: &str, function: &str) -> String {
+        check_name(prefix, function);
+        let function = function.replace("::", ".");
+        format!("{}_{}_{}_{:?}", PREFIX, prefix, function, self.state.finish())
+    }
+}
+
+/// Panics if `prefix` contains `_`, which separates it from the function name, or if `prefix` or
+/// `function` contains characters which are not allowed in file names, such as `/` or a `:` which
+/// is not part of a `::`. The macros call it in a constant, such that invalid names are rejected
+/// at compile time.
+///
+/// # Example
+///
+/// ```
+/// use persistentcache::key::check_name;
+///
+/// const _: () = check_name("DEF", "ops::triple");
+/// ```
+///
+/// An invalid prefix or name passed to a macro fails to compile:
+///
+/// ```compile_fail
+/// #[macro_use]
+/// extern crate persistentcache;
+/// use persistentcache::storage::memory::MemoryStorage;
+/// use persistentcache::PersistentCache;
+///
+/// fn main() {
+///     let mut s = MemoryStorage::new();
+///     cache!(s, "results/sum", [1, 2], || 1 + 2);
+/// }
+/// ```
+pub const fn check_name(prefix: &str, function: &str) {
+    let prefix = prefix.as_bytes();
+    let mut i = 0;
+    while i < prefix.len() {
+        if prefix[i] == b'_' {
+            panic!("The prefix of a cached function must not contain `_`");
+        }
+        if is_invalid(prefix[i]) || prefix[i] == b':' {
+            panic!("The prefix of a cached function must not contain characters which are not allowed in file names");
+        }
+        i += 1;
+    }
+    let function = function.as_bytes();
+    let mut i = 0;
+    while i < function.len() {
+        if function[i] == b':' && i + 1 < function.len() && function[i + 1] == b':' {
+            i += 2;
+            continue;
+        }
+        if is_invalid(function[i]) || function[i] == b':' {
+            panic!("The name of a cached function must not contain characters which are not allowed in file names");
+        }
+        i += 1;
+    }
+}
+
+/// Returns whether `c` is a control character or one of `INVALID_CHARS`.
+const fn is_invalid(c: u8) -> bool {
+    let mut i = 0;
+    while i < INVALID_CHARS.len() {
+        if c == INVALID_CHARS[i] {
+            return true;
+        }
+        i += 1;
+    }
+    c < 0x20 || c == 0x7f
+}
+
+/// Splits a name returned by `KeyBuilder::name` into the prefix and the function name. Returns
+/// `None` for names of another form. Function names may contain `_`, prefixes may not.
+///
+/// # Example
+///
+/// ```
+/// use persistentcache::key::split_name;
+///
+/// assert_eq!(split_name("pc_fu_add_two_123"), Some(("fu", "add_two")));
+/// assert_eq!(split_name("pc_DEF_ops.triple_123"), Some(("DEF", "ops.triple")));
+/// assert_eq!(split_name("add_two"), None);
+/// ```
+pub fn split_name(name: &str) -> Option<(&str, &str)> {
+    if !name.starts_with(PREFIX) || !name[PREFIX.len()..].starts_with('_') {
+        return None;
+    }
+    let rest = &name[PREFIX.len() + 1..];
+    let sep = rest.rfind('_')?;
+    if rest[sep + 1..].parse::<u64>().is_err() {
+        return None;
+    }
+    let mut parts = rest[..sep].splitn(2, '_');
+    match (parts.next(), parts.next()) {
+        (Some(prefix), Some(function)) if !function.is_empty() => Some((prefix, function)),
+        _ => None,
+    }
+}
+
+impl Default for KeyBuilder {
+    fn default() -> Self {
+        KeyBuilder::new()
+    }
+}
+
+/// Allows to add further values, such as options, to the key after the arguments.
+impl Hasher for KeyBuilder {
+    fn finish(&self) -> u64 {
+        self.state.finish()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.state.write(bytes);
+    }
+}
+
+/// Wrapper around an argument which is used by the macros to hash the argument via `CacheKey` if
+/// it is implemented and via `Hash` otherwise.
+#[doc(hidden)]
+pub struct KeyArg<'a, T: 'a + ?Sized>(pub &'a T);
+
+/// Hash a `KeyArg` via `CacheKey`.
+#[doc(hidden)]
+pub trait ViaCacheKey {
+    /// Add the wrapped argument to `key`.
+    fn add_to(&self, key: &mut KeyBuilder);
+}
+
+impl<'a, T: CacheKey + ?Sized> ViaCacheKey for KeyArg<'a, T> {
+    fn add_to(&self, key: &mut KeyBuilder) {
+        key.arg::<T, _>(|state| self.0.cache_key(state));
+    }
+}
+
+/// Hash a `KeyArg` via `Hash`. This is only picked by method resolution if `ViaCacheKey` does not
+/// apply because it is implemented on a reference.
+#[doc(hidden)]
+pub trait ViaHash {
+    /// Add the wrapped argument to `key`.
+    fn add_to(&self, key: &mut KeyBuilder);
+}
+
+impl<'a, 'b, T: Hash + ?Sized> ViaHash for &'b KeyArg<'a, T> {
+    fn add_to(&self, key: &mut KeyBuilder) {
+        key.arg::<T, _>(|state| self.0.hash(state));
+    }
+}
+
+/// Calls a key function with references to the arguments of a cached function. Implemented for
+/// tuples of references such that the types of the closure parameters can be inferred.
+#[doc(hidden)]
+pub trait KeyFn<F> {
+    /// Value returned by the key function
+    type Output;
+    /// Call `f` with the elements of the tuple.
+    fn apply_key(self, f: F) -> Self::Output;
+}
+
+macro_rules! key_fn_tuple {
+    ($(($($t:ident . $i:tt),*)),*) => {
+        $(
+            impl<'a, $($t: 'a + ?Sized,)* Ret, Fun> KeyFn<Fun> for ($(&'a $t,)*)
+            where
+                Fun: FnOnce($(&'a $t),*) -> Ret,
+            {
+                type Output = Ret;
+                #[allow(unused_variables)]
+                fn apply_key(self, f: Fun) -> Ret {
+                    f($(self.$i),*)
+                }
+            }
+        )*
+    };
+}
+
+key_fn_tuple!(
+    (),
+    (A.0),
+    (A.0, B.1),
+    (A.0, B.1, C.2),
+    (A.0, B.1, C.2, D.3),
+    (A.0, B.1, C.2, D.3, E.4),
+    (A.0, B.1, C.2, D.3, E.4, F.5),
+    (A.0, B.1, C.2, D.3, E.4, F.5, G.6),
+    (A.0, B.1, C.2, D.3, E.4, F.5, G.6, H.7),
+    (A.0, B.1, C.2, D.3, E.4, F.5, G.6, H.7, I.8),
+    (A.0, B.1, C.2, D.3, E.4, F.5, G.6, H.7, I.8, J.9),
+    (A.0, B.1, C.2, D.3, E.4, F.5, G.6, H.7, I.8, J.9, K.10),
+    (A.0, B.1, C.2, D.3, E.4, F.5, G.6, H.7, I.8, J.9, K.10, L.11)
+);
@@ -15,8 +15,8 @@
 //! storage. This can lead to an decrease in computing time in case the function call is
 //! computationally more expensive than fetching the value from the storage. The storages are
 //! persistent (stored on disk) and can be shared between different threads and processes.
-//! All parameters of the function to be cached need to be Hashable. The return value needs to be
-//! serializeable by the crate `bincode`.
+//! All parameters of the function to be cached need to implement either `Hash` or `CacheKey`. The
+//! return value needs to implement `serde::Serialize` and `serde::Deserialize`.
 //!
 //! There are two different ways of caching:
 //!
@@ -43,6 +43,8 @@
 //! `#[persistent_cache]` directive. This modifies the function such that values never computed
 //! before are computed and cached in a storage. Already computed values are fetched from said
 //! storage without computing. The return type needs to implement the `Serializable` trait.
+//! Arguments may be patterns such as `(a, b): (u64, u64)` or of type `impl Hash`; such arguments
+//! are hashed as a whole before they are destructured.
 //!
 //! ## Example
 //!
@@ -213,10 +215,301 @@
 //! 5
 //! ```
 //!
+//! Paths, method calls and closures can be cached as well, see `cache!` for details:
+//!
+//! ```text
+//! cache!(s, module::add_two(2));
+//! cache!(s, self.model.solve(t), key = |t| (self.model.id, *t));
+//! cache!(s, "sweep", [a, b], || sweep(&a, &b));
+//! ```
+//!
+//! # Excluding arguments from the cache key
+//!
+//! Arguments which should not influence the cache key, such as loggers, progress bars or
+//! counters, can be marked with `#[cache_ignore]`. They are still passed to the function. This
+//! works with all three macros:
+//!
+//! ```text
+//! #[persistent_cache]
+//! #[params(FileStorage, "test_dir")]
+//! fn add_two(a: u64, #[cache_ignore] counter: &mut u64) -> u64 { ... }
+//!
+//! cache_func!(File, "test_dir",
+//! fn add_two(a: u64, #[cache_ignore] counter: &mut u64) -> u64 { ... });
+//!
+//! cache!(s, add_two(2, #[cache_ignore] &mut counter));
+//! ```
+//!
+//! # Custom cache keys
+//!
+//! `CacheKey` is implemented for floats and containers of floats, see the `key` module for
+//! details. Arguments which implement neither `Hash` nor `CacheKey`, or which should be keyed by
+//! something else, can be handled with a key function. It receives references to all arguments
+//! and returns the value which is hashed instead of the arguments:
+//!
+//! ```text
+//! #[persistent_cache(key = |m, _| m.digest())]
+//! #[params(FileStorage, "test_dir")]
+//! fn solve(m: &Matrix, log: &mut Logger) -> Vec<f64> { ... }
+//!
+//! cache_func!(File, "test_dir", [key = |m, _| m.digest()],
+//! fn solve(m: &Matrix, log: &mut Logger) -> Vec<f64> { ... });
+//!
+//! cache!(s, solve(&m, &mut log), key = |m, _| m.digest());
+//! ```
+//!
+//! # Invalidating cached results
+//!
+//! Cached results are only keyed by the function name and the arguments. If the body of a
+//! function changes, an explicit version should be provided which is bumped with every change
+//! affecting the results. `#[persistent_cache]` additionally supports `version = auto`, which
+//! derives the version from the tokens of the function, such that every change to the function
+//! invalidates the cached results.
+//!
+//! ```text
+//! #[persistent_cache(version = auto)]
+//! #[params(FileStorage, "test_dir")]
+//! fn add_two(a: u64) -> u64 { ... }
+//!
+//! cache_func!(File, "test_dir", [version = 2],
+//! fn add_two(a: u64) -> u64 { ... });
+//!
+//! cache!(s, add_two(2), version = 2);
+//! ```
+//!
+//! Every stored value carries a fingerprint of its type. If the return type changes, e.g. from
+//! `Vec<f32>` to `Vec<f64>`, stored values are treated as missing and computed again. If the
+//! serialized form of a type changes while its name stays the same, e.g. when a struct gains a
+//! field, bump the option `schema = ...` to the same effect. Unlike `version`, this overwrites the
+//! old values instead of storing the new ones alongside them.
+//!
+//! # Skipping cheap or large results
+//!
+//! Fetching a result from the storage is not always faster than computing it again, and very
+//! large results may not be worth the space. With `min_duration`, only results whose computation
+//! took at least the given time are stored, with `max_size`, only results which take at most the
+//! given number of bytes in the storage. Skipped results are still returned and counted as misses.
+//!
+//! ```text
+//! cache!(s, add_two(2), min_duration = Duration::from_millis(100), max_size = 1 << 20);
+//! ```
+//!
+//! Results which must not be reused, e.g. the output of a solver which did not converge, can be
+//! rejected with a predicate which receives a reference to the computed result:
+//!
+//! ```text
+//! #[persistent_cache(cache_if = |r: &Solution| r.converged)]
+//! #[params(FileStorage, "test_dir")]
+//! fn solve(m: &Matrix) -> Solution { ... }
+//!
+//! cache_func!(File, "test_dir", [cache_if = |r: &Vec<f64>| r.iter().all(|x| x.is_finite())],
+//! fn simulate(t: u64) -> Vec<f64> { ... });
+//!
+//! cache!(s, solve(&m), cache_if = |r| r.converged);
+//! ```
+//!
+//! # Serialization formats
+//!
+//! Values are serialized with `bincode` by default. JSON, MessagePack and CBOR are available with
+//! the features `json`, `msgpack` and `cbor`; they can be read from other languages and tolerate
+//! added struct fields. The codec is chosen either for a whole storage or per function, where the
+//! latter takes precedence:
+//!
+//! ```text
+//! let s = RedisStorage::new("redis://127.0.0.1").unwrap().with_codec(Codec::Json);
+//!
+//! #[persistent_cache(codec = Codec::Json)]
+//! #[params(FileStorage, "test_dir")]
+//! fn add_two(a: u64) -> u64 { ... }
+//!
+//! cache!(s, add_two(2), codec = Codec::MessagePack);
+//! ```
+//!
+//! # Compression
+//!
+//! With the features `zstd` or `lz4`, any storage can be wrapped in a `CompressedStorage`, which
+//! compresses values before they are stored. Values stored without compression remain readable.
+//!
+//! ```text
+//! let s = CompressedStorage::new(FileStorage::new("test_dir").unwrap(), Compression::Zstd(3));
+//! ```
+//!
+//! # Encryption
+//!
+//! With the feature `encryption`, any storage can be wrapped in an `EncryptedStorage`, which
+//! encrypts values with ChaCha20-Poly1305 and a 256 bit key. Values which were tampered with are
+//! reported and computed again.
+//!
+//! ```text
+//! let s = EncryptedStorage::new(RedisStorage::new("redis://127.0.0.1").unwrap(), &key);
+//! ```
+//!
+//! # Caching many calls at once
+//!
+//! `cache_map!` looks up the results of a function for many arguments in one batch and computes
+//! only the missing ones. With a `RedisStorage`, this takes two round trips in total instead of
+//! one or two per call.
+//!
+//! ```text
+//! let params = vec![(1, 2), (3, 4)];
+//! let results: Vec<u64> = cache_map!(s, add(a, b) for (a, b) in params);
+//! ```
+//!
+//! # Storage locations
+//!
+//! The location of the storage of `cache_func!` and `#[persistent_cache]` may be an expression,
+//! which is evaluated on the first call. Without a location, the environment variables
+//! `PERSISTENTCACHE_DIR` and `PERSISTENTCACHE_REDIS_URL` or a config file decide, such that the same
+//! binary can use different caches on different machines, see the `config` module.
+//!
+//! ```text
+//! #[persistent_cache]
+//! #[params(FileStorage, std::env::var("SCRATCH").unwrap() + "/cache")]
+//! fn add_two(a: u64) -> u64 { ... }
+//!
+//! #[persistent_cache]
+//! #[params(RedisStorage)]
+//! fn add_three(a: u64) -> u64 { ... }
+//!
+//! cache_func!(File, fn add_four(a: u64) -> u64 { ... });
+//!
+//! let s = FileStorage::from_config().unwrap();
+//! ```
+//!
+//! # Sharing storages between functions
+//!
+//! Storages can be registered once under a name and used by many functions, instead of every
+//! function creating its own storage and connection, see the `registry` module:
+//!
+//! ```text
+//! registry::register("main", RedisStorage::new("redis://127.0.0.1").unwrap());
+//!
+//! #[persistent_cache(storage = "main")]
+//! fn add_two(a: u64) -> u64 { ... }
+//!
+//! cache_func!(Named, "main", fn add_three(a: u64) -> u64 { ... });
+//! ```
+//!
+//! # Bypassing the cache
+//!
+//! The environment variable `PERSISTENTCACHE_MODE` changes the behaviour of all macros in a
+//! process without recompiling: `off` computes every result without accessing the storages,
+//! `readonly` fetches cached results but does not store computed ones and `refresh` computes every
+//! result and overwrites the cached one. `set_mode` sets the mode from within the program.
+//!
+//! ```bash
+//! PERSISTENTCACHE_MODE=refresh cargo run
+//! ```
+//!
+//! # Statistics
+//!
+//! The hits and misses, the transferred bytes and the time spent in the storage and computing are
+//! recorded per function. `stats()` returns them as a serializable report:
+//!
+//! ```text
+//! let report = persistentcache::stats();
+//! println!("{}", serde_json::to_string(&report).unwrap());
+//! ```
+//!
+//! # Tracing
+//!
+//! With the feature `tracing`, every cached call runs in a `persistentcache` span carrying the
+//! function name and the type of the storage. Within it, hits and misses are reported as `DEBUG`
+//! events with the key and the number of bytes, storage accesses and computations as `TRACE`
+//! events with their duration. Failing storage accesses are reported as `ERROR` events before
+//! the macros panic, and problems the storages recover from, such as values which cannot be
+//! decrypted, as `WARN` events instead of on stderr.
+//!
+//! ```toml
+//! [dependencies]
+//! persistentcache = { version = "*", features = ["tracing"] }
+//! ```
+//!
+//! # Entry metadata
+//!
+//! Every value is stored together with the name of the function, the time it was computed and how
+//! long computing it took. `PersistentCache::metadata` returns this `Metadata` along with the size
+//! of the entry and, for storages which keep track of it, the time the entry was last read:
+//!
+//! ```text
+//! for name in s.keys().unwrap() {
+//!     let meta = s.metadata(&name).unwrap().unwrap();
+//!     println!("{}: {} s", meta.function, meta.compute_secs);
+//! }
+//! ```
+//!
+//! With the option `record_args = true`, the `Debug` representation of the arguments which are
+//! part of the key is stored as well, e.g. to find out which points of a parameter sweep are
+//! already computed. Arguments which do not implement `Debug` are recorded as `_`:
+//!
+//! ```text
+//! cache!(s, simulate(temperature, pressure), record_args = true);
+//! ```
+//!
+//! ```bash
+//! persistentcache test_dir list --function simulate --args "300.0"
+//! ```
+//!
+//! # Moving caches between storages
+//!
+//! `archive::export` writes all entries of a storage into a single archive file, which
+//! `archive::import` stores in another storage, e.g. to ship results precomputed on a cluster with
+//! a `RedisStorage` to a laptop using a `FileStorage`. `archive::copy_between` copies the entries
+//! directly if both storages are reachable. Both storages need to use the same codec. Times to
+//! live are preserved if both storages let variables expire, e.g. between Redis servers.
+//!
+//! # Command line tool
+//!
+//! The binary `persistentcache` inspects and manages the entries of a `FileStorage` directory or
+//! of a Redis server:
+//!
+//! ```bash
+//! persistentcache test_dir list --function add_two
+//! persistentcache redis://127.0.0.1 stats
+//! persistentcache test_dir show pc_DEF_add_two_5025358914766432393
+//! persistentcache test_dir delete --prefix fu --older-than 7d
+//! persistentcache test_dir top 10
+//! persistentcache redis://127.0.0.1 export results.pcar
+//! persistentcache test_dir import results.pcar
+//! ```
+//!
+//! Entries are grouped by function name and prefix. Ages are taken from the metadata of the
+//! entries, `top` lists the entries which took longest to compute. `show` prints values encoded
+//! with JSON, MessagePack or CBOR as JSON if the feature `json` is enabled besides the feature of
+//! the codec, and other values as text or hex dump.
 //!
 //! # Implementing other storages
 //!
-//! Storages need to implement the `PersistentCache` trait.
+//! Storages need to implement the `PersistentCache` trait. Storages which support fetching or
+//! storing several values at once should override `get_many` and `set_many`. Storages which can
+//! enumerate and delete single values should override `keys` and `delete`, and `modified` if they
+//! know when values were written, and `ttl` and `set_ttl` if values expire.
+//!
+//! `PersistentCache` is object safe. `Box<dyn PersistentCache>` and `&mut dyn PersistentCache`
+//! implement it as well, such that storages chosen at runtime work with all macros and wrappers.
+//! The storage passed to `cache!` and `cache_map!` may be any expression which is evaluated once,
+//! e.g. a field or a locked storage:
+//!
+//! ```text
+//! struct Plugin {
+//!     cache: Box<dyn PersistentCache + Send>,
+//! }
+//!
+//! cache!(self.cache, add_two(2));
+//! cache!(&mut *guard, add_two(2));
+//! cache!(storages.get_mut("main").unwrap(), add_two(2));
+//! ```
+//!
+//! Since all methods which access the storage take `&mut self`, storages which are shared need to
+//! be behind a mutex. `Mutex<S>`, `&Mutex<S>` and `Arc<Mutex<S>>` implement `PersistentCache` by
+//! locking the mutex for every access, including `&Mutex<dyn PersistentCache + Send>` and the
+//! storages of the `registry`:
+//!
+//! ```text
+//! let storages: HashMap<&str, Mutex<Box<dyn PersistentCache + Send>>> = ...;
+//! cache!(storages.get("main").unwrap(), add_two(2));
+//! cache!(registry::storage("main").unwrap(), add_two(2));
+//! ```
 //!
 //! # Running the tests
 //!
@@ -243,14 +536,30 @@
 #![allow(unused_imports)]
 #![warn(missing_docs)]
 #![feature(proc_macro_hygiene)]
+extern crate bincode;
+#[cfg(feature = "encryption")]
+extern crate chacha20poly1305;
 #[macro_use]
 extern crate error_chain;
 extern crate fs2;
 #[macro_use]
 extern crate lazy_static;
+#[cfg(feature = "lz4")]
+extern crate lz4_flex;
 extern crate persistentcache_procmacro;
 extern crate redis;
 extern crate regex;
+#[cfg(feature = "msgpack")]
+extern crate rmp_serde;
+extern crate serde;
+#[cfg(feature = "cbor")]
+extern crate serde_cbor;
+#[cfg(feature = "json")]
+extern crate serde_json;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "zstd")]
+extern crate zstd;
 
 use persistentcache_procmacro::persistent_cache;
 
@@ -265,16 +574,48 @@ mod errors {
 }
 
 use errors::*;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
+pub mod archive;
+pub mod codec;
+pub mod config;
+pub mod entry;
+pub mod key;
+pub mod mode;
+pub mod options;
 #[macro_use]
 pub mod persistentcache;
+pub mod registry;
+pub mod stats;
 pub mod storage;
 
+pub use codec::Codec;
+pub use entry::Metadata;
+pub use key::CacheKey;
+pub use mode::{set_mode, Mode};
+pub use options::CacheOptions;
+pub use stats::{reset_stats, stats};
+// Needed in scope for the code generated by `#[persistent_cache]`
+#[doc(hidden)]
+pub use entry::{DebugArg, ViaDebug, ViaNoDebug};
+#[doc(hidden)]
+pub use key::{KeyArg, KeyBuilder, KeyFn, ViaCacheKey, ViaHash};
+
 /// Every stored variable is prefixed by this string. Currently, the flush functions depend on this
 /// in order to decide which variable to flush from the storage. Keeping track of the used variable
 /// internally is not an option because they are persistent and may come from another process.
 pub const PREFIX: &str = "pc";
 
+/// Reports a problem which does not interrupt caching, as a `tracing` warning if the feature
+/// `tracing` is enabled and on stderr otherwise.
+fn warn(message: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!("{}", message);
+    #[cfg(not(feature = "tracing"))]
+    eprintln!("persistentcache: {}", message);
+}
+
 /// Traits which need to be implemented by any storage
 pub trait PersistentCache {
     /// Return serialized value of variable
@@ -283,8 +624,153 @@ pub trait PersistentCache {
     fn set(&mut self, &str, &[u8]) -> Result<()>;
     /// Flush storage
     fn flush(&mut self) -> Result<()>;
+    /// Codec used for values stored in this storage unless a function overrides it
+    fn codec(&self) -> Codec {
+        Codec::Bincode
+    }
+    /// Return serialized values of several variables, in the same order as `names`
+    fn get_many(&mut self, names: &[&str]) -> Result<Vec<Vec<u8>>> {
+        names.iter().map(|name| self.get(name)).collect()
+    }
+    /// Set serialized values of several variables
+    fn set_many(&mut self, entries: &[(&str, &[u8])]) -> Result<()> {
+        for &(name, val) in entries {
+            self.set(name, val)?;
+        }
+        Ok(())
+    }
+    /// Return the names of all stored variables which start with `PREFIX_`
+    fn keys(&mut self) -> Result<Vec<String>> {
+        bail!("Listing variables is not supported by this storage")
+    }
+    /// Delete a single variable. Deleting a variable which is not stored is not an error.
+    fn delete(&mut self, _name: &str) -> Result<()> {
+        bail!("Deleting single variables is not supported by this storage")
+    }
+    /// Return when the variable was last written, if the storage keeps track of it
+    fn modified(&mut self, _name: &str) -> Result<Option<SystemTime>> {
+        Ok(None)
+    }
+    /// Return how long the variable is kept until it expires, if the storage lets variables expire
+    fn ttl(&mut self, _name: &str) -> Result<Option<Duration>> {
+        Ok(None)
+    }
+    /// Let the variable expire after `ttl`. Storages which do not let variables expire keep it.
+    fn set_ttl(&mut self, _name: &str, _ttl: Duration) -> Result<()> {
+        Ok(())
+    }
+    /// Return when the variable was last read, if the storage keeps track of it
+    fn accessed(&mut self, _name: &str) -> Result<Option<SystemTime>> {
+        Ok(None)
+    }
+    /// Return the metadata stored with the variable, `None` if it is not stored or has none
+    fn metadata(&mut self, name: &str) -> Result<Option<Metadata>> {
+        // Reading the value counts as an access
+        let accessed = self.accessed(name)?;
+        let val = self.get(name)?;
+        Ok(entry::metadata(&val).map(|meta| Metadata { accessed, ..meta }))
+    }
+}
+
+/// Implements `PersistentCache` for a pointer to a storage, or with `lock` for a mutex holding a
+/// storage, by forwarding all methods.
+macro_rules! forward_persistent_cache {
+    ($(#[$attr:meta])* impl<$($l:lifetime,)* S> for $t:ty) => {
+        forward_persistent_cache!($(#[$attr])* impl<$($l,)* S> for $t, deref);
+    };
+    ($(#[$attr:meta])* impl<$($l:lifetime,)* S> for $t:ty, $via:ident) => {
+        $(#[$attr])*
+        impl<$($l,)* S: PersistentCache + ?Sized> PersistentCache for $t {
+            fn get(&mut self, name: &str) -> Result<Vec<u8>> {
+                forward_persistent_cache!(@inner $via self).get(name)
+            }
+
+            fn set(&mut self, name: &str, val: &[u8]) -> Result<()> {
+                forward_persistent_cache!(@inner $via self).set(name, val)
+            }
+
+            fn flush(&mut self) -> Result<()> {
+                forward_persistent_cache!(@inner $via self).flush()
+            }
+
+            fn codec(&self) -> Codec {
+                forward_persistent_cache!(@inner $via self).codec()
+            }
+
+            fn get_many(&mut self, names: &[&str]) -> Result<Vec<Vec<u8>>> {
+                forward_persistent_cache!(@inner $via self).get_many(names)
+            }
+
+            fn set_many(&mut self, entries: &[(&str, &[u8])]) -> Result<()> {
+                forward_persistent_cache!(@inner $via self).set_many(entries)
+            }
+
+            fn keys(&mut self) -> Result<Vec<String>> {
+                forward_persistent_cache!(@inner $via self).keys()
+            }
+
+            fn delete(&mut self, name: &str) -> Result<()> {
+                forward_persistent_cache!(@inner $via self).delete(name)
+            }
+
+            fn modified(&mut self, name: &str) -> Result<Option<SystemTime>> {
+                forward_persistent_cache!(@inner $via self).modified(name)
+            }
+
+            fn ttl(&mut self, name: &str) -> Result<Option<Duration>> {
+                forward_persistent_cache!(@inner $via self).ttl(name)
+            }
+
+            fn set_ttl(&mut self, name: &str, ttl: Duration) -> Result<()> {
+                forward_persistent_cache!(@inner $via self).set_ttl(name, ttl)
+            }
+
+            fn accessed(&mut self, name: &str) -> Result<Option<SystemTime>> {
+                forward_persistent_cache!(@inner $via self).accessed(name)
+            }
+
+            fn metadata(&mut self, name: &str) -> Result<Option<Metadata>> {
+                forward_persistent_cache!(@inner $via self).metadata(name)
+            }
+        }
+    };
+    (@inner deref $s:ident) => {
+        (**$s)
+    };
+    (@inner lock $s:ident) => {
+        $s.lock().unwrap()
+    };
 }
 
+forward_persistent_cache!(
+    /// Allows to use a storage in a `TieredStorage` or another wrapper without giving up
+    /// ownership, and `&mut dyn PersistentCache` wherever a storage is expected.
+    impl<'a, S> for &'a mut S
+);
+forward_persistent_cache!(
+    /// Allows to use `Box<dyn PersistentCache>` wherever a storage is expected, e.g. for storages
+    /// chosen at runtime.
+    impl<S> for Box<S>
+);
+forward_persistent_cache!(
+    /// Allows to use a storage behind a mutex wherever a storage is expected. The mutex is locked
+    /// for every single access.
+    impl<S> for Mutex<S>,
+    lock
+);
+forward_persistent_cache!(
+    /// Allows to use a shared reference to a storage behind a mutex, e.g.
+    /// `&Mutex<dyn PersistentCache + Send>`, wherever a storage is expected.
+    impl<'a, S> for &'a Mutex<S>,
+    lock
+);
+forward_persistent_cache!(
+    /// Allows to use a storage shared between threads, e.g. a `registry::SharedStorage`, wherever
+    /// a storage is expected.
+    impl<S> for Arc<Mutex<S>>,
+    lock
+);
+
 #[cfg(test)]
 mod tests {
     extern crate num;
@@ -403,50 +889,1042 @@ mod tests {
     }
 
     #[test]
-    fn test_redis_storage() {
-        let a: i64 = 6;
-        let mut counter: i64 = 0;
-        let mut s = RedisStorage::new("redis://127.0.0.1").unwrap();
+    fn test_func_procmacro_cache_ignore() {
+        let mut s = FileStorage::new("file_test").unwrap();
         s.flush().unwrap();
-        assert_eq!(a * 10, test_func_1(a, &mut counter));
+        let mut counter: i64 = 0;
+
+        #[persistent_cache]
+        #[params(FileStorage, "file_test")]
+        fn test_func_proc(a: &Vec<i64>, #[cache_ignore] counter: &mut i64) -> Vec<i64> {
+            *counter += 1;
+            vec![a[1], a[0]]
+        }
+
+        assert_eq!(vec![1, 2], test_func_proc(&vec![2, 1], &mut counter));
         assert_eq!(counter, 1);
-        assert_eq!(a * 10, cache!(s, test_func_1(a, &mut counter)));
-        assert_eq!(counter, 2);
-        let mut counter: i64 = 1;
-        assert_eq!(a * 10, cache!(s, test_func_1(a, &mut counter)));
+        assert_eq!(vec![1, 2], test_func_proc(&vec![2, 1], &mut counter));
         assert_eq!(counter, 1);
         s.flush().unwrap();
     }
 
     #[test]
-    fn test_file_storage() {
-        let a: i64 = 6;
-        let mut counter: i64 = 0;
+    fn test_func_cache_ignore() {
         let mut s = FileStorage::new("file_test").unwrap();
         s.flush().unwrap();
-        assert_eq!(a * 10, test_func_1(a, &mut counter));
+        let mut counter: i64 = 0;
+        cache_func!(
+            File,
+            "file_test",
+            fn mul_ten(n: i64, #[cache_ignore] counter: &mut i64) -> i64 {
+                *counter += 1;
+                n * 10
+            }
+        );
+        assert_eq!(60, mul_ten(6, &mut counter));
         assert_eq!(counter, 1);
-        assert_eq!(a * 10, cache!(s, test_func_1(a, &mut counter)));
-        assert_eq!(counter, 2);
-        let mut counter: i64 = 1;
-        assert_eq!(a * 10, cache!(s, test_func_1(a, &mut counter)));
+        assert_eq!(60, mul_ten(6, &mut counter));
         assert_eq!(counter, 1);
         s.flush().unwrap();
     }
 
     #[test]
-    fn test_hashing() {
-        // swapping the indices should change the hashes!
-        let a: i64 = 6;
-        let b: i64 = 2;
+    fn test_func_procmacro_key() {
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
         let mut counter: i64 = 0;
+
+        #[persistent_cache(key = |a, _| a.len())]
+        #[params(FileStorage, "file_test")]
+        fn test_func_proc(a: &Vec<i64>, counter: &mut i64) -> usize {
+            *counter += 1;
+            a.len()
+        }
+
+        assert_eq!(2, test_func_proc(&vec![2, 1], &mut counter));
+        assert_eq!(counter, 1);
+        assert_eq!(2, test_func_proc(&vec![3, 4], &mut counter));
+        assert_eq!(counter, 1);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_func_procmacro_patterns() {
         let mut s = FileStorage::new("file_test").unwrap();
         s.flush().unwrap();
-        assert_eq!(a * b, cache!(s, test_func_2(a, b, &mut counter)));
+        let mut counter: i64 = 0;
+
+        #[persistent_cache]
+        #[params(FileStorage, "file_test")]
+        fn test_func_proc(
+            (a, b): (i64, i64),
+            c: impl std::hash::Hash,
+            #[cache_ignore] counter: &mut i64,
+        ) -> i64 {
+            *counter += 1;
+            let _ = c;
+            a + b
+        }
+
+        assert_eq!(3, test_func_proc((1, 2), "c", &mut counter));
+        assert_eq!(3, test_func_proc((1, 2), "c", &mut counter));
         assert_eq!(counter, 1);
+        assert_eq!(3, test_func_proc((2, 1), "c", &mut counter));
+        assert_eq!(3, test_func_proc((1, 2), 'c', &mut counter));
+        assert_eq!(counter, 3);
+
+        #[derive(Hash)]
+        struct Point {
+            x: i64,
+            y: i64,
+        }
+
+        #[persistent_cache]
+        #[params(FileStorage, "file_test")]
+        fn test_func_struct(Point { x, y }: Point, #[cache_ignore] counter: &mut i64) -> i64 {
+            *counter += 1;
+            x - y
+        }
+
+        assert_eq!(1, test_func_struct(Point { x: 3, y: 2 }, &mut counter));
+        assert_eq!(1, test_func_struct(Point { x: 3, y: 2 }, &mut counter));
+        assert_eq!(counter, 4);
+        assert_eq!(-1, test_func_struct(Point { x: 2, y: 3 }, &mut counter));
+        assert_eq!(counter, 5);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_func_procmacro_version() {
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
         let mut counter: i64 = 0;
-        assert_eq!(a * b, cache!(s, test_func_2(b, a, &mut counter)));
+
+        #[persistent_cache(version = auto)]
+        #[params(FileStorage, "file_test")]
+        fn test_func_proc(a: &Vec<i64>, #[cache_ignore] counter: &mut i64) -> Vec<i64> {
+            *counter += 1;
+            vec![a[1], a[0]]
+        }
+
+        assert_eq!(vec![1, 2], test_func_proc(&vec![2, 1], &mut counter));
+        assert_eq!(vec![1, 2], test_func_proc(&vec![2, 1], &mut counter));
         assert_eq!(counter, 1);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_func_version() {
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        let mut counter: i64 = 0;
+        {
+            cache_func!(
+                File,
+                "file_test",
+                [version = 1],
+                fn add_two(n: u64, #[cache_ignore] counter: &mut i64) -> u64 {
+                    *counter += 1;
+                    n + 2
+                }
+            );
+            assert_eq!(12, add_two(10, &mut counter));
+            assert_eq!(12, add_two(10, &mut counter));
+            assert_eq!(counter, 1);
+        }
+        {
+            cache_func!(
+                File,
+                "file_test",
+                [version = 2],
+                fn add_two(n: u64, #[cache_ignore] counter: &mut i64) -> u64 {
+                    *counter += 1;
+                    n + 3
+                }
+            );
+            assert_eq!(13, add_two(10, &mut counter));
+            assert_eq!(counter, 2);
+        }
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_func_key() {
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        let mut counter: i64 = 0;
+        cache_func!(
+            File,
+            "file_test",
+            [key = |a, _| a.round() as i64],
+            fn round_ten(a: f64, counter: &mut i64) -> f64 {
+                *counter += 1;
+                a.round() * 10.0
+            }
+        );
+        assert_eq!(10.0, round_ten(1.2, &mut counter));
+        assert_eq!(counter, 1);
+        assert_eq!(10.0, round_ten(0.8, &mut counter));
+        assert_eq!(counter, 1);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_redis_storage() {
+        let a: i64 = 6;
+        let mut counter: i64 = 0;
+        let mut s = RedisStorage::new("redis://127.0.0.1").unwrap();
+        s.flush().unwrap();
+        assert_eq!(a * 10, test_func_1(a, &mut counter));
+        assert_eq!(counter, 1);
+        assert_eq!(a * 10, cache!(s, test_func_1(a, &mut counter)));
+        assert_eq!(counter, 2);
+        let mut counter: i64 = 1;
+        assert_eq!(a * 10, cache!(s, test_func_1(a, &mut counter)));
+        assert_eq!(counter, 1);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_file_storage() {
+        let a: i64 = 6;
+        let mut counter: i64 = 0;
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        assert_eq!(a * 10, test_func_1(a, &mut counter));
+        assert_eq!(counter, 1);
+        assert_eq!(a * 10, cache!(s, test_func_1(a, &mut counter)));
+        assert_eq!(counter, 2);
+        let mut counter: i64 = 1;
+        assert_eq!(a * 10, cache!(s, test_func_1(a, &mut counter)));
+        assert_eq!(counter, 1);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn test_compressed_storage() {
+        use storage::compressed::{CompressedStorage, Compression};
+
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        // Uncompressed values written before the storage was wrapped are still readable
+        s.set("pc_uncompressed", &[1, 2, 3]).unwrap();
+        let mut s = CompressedStorage::new(s, Compression::Lz4);
+        assert_eq!(s.get("pc_uncompressed").unwrap(), vec![1, 2, 3]);
+
+        let val = vec![0_u8; 1000];
+        s.set("pc_compressed", &val).unwrap();
+        assert_eq!(s.get("pc_compressed").unwrap(), val);
+        let mut s = s.into_inner();
+        assert!(s.get("pc_compressed").unwrap().len() < 100);
+
+        // Values compressed with an unknown algorithm or corrupted are misses
+        let mut unknown = storage::compressed::HEADER.to_vec();
+        unknown.extend(&[9, 1, 2, 3]);
+        s.set("pc_unknown", &unknown).unwrap();
+        let mut corrupt = storage::compressed::HEADER.to_vec();
+        corrupt.extend(&[2, 255, 255, 255, 255]);
+        s.set("pc_corrupt", &corrupt).unwrap();
+        let mut s = CompressedStorage::new(s, Compression::Lz4);
+        assert!(s.get("pc_unknown").unwrap().is_empty());
+        assert_eq!(s.get_many(&["pc_corrupt"]).unwrap(), vec![Vec::<u8>::new()]);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn test_encrypted_storage() {
+        use storage::encrypted::EncryptedStorage;
+
+        let a: i64 = 6;
+        let mut counter: i64 = 0;
+        let s = FileStorage::new("file_test").unwrap();
+        let mut s = EncryptedStorage::new(s, &[1; 32]);
+        s.flush().unwrap();
+        assert_eq!(a * 10, cache!(s, test_func_1(a, #[cache_ignore] &mut counter)));
+        assert_eq!(a * 10, cache!(s, test_func_1(a, #[cache_ignore] &mut counter)));
+        assert_eq!(counter, 1);
+
+        // Values encrypted with another key are misses
+        let mut s = EncryptedStorage::new(s.into_inner(), &[2; 32]);
+        assert_eq!(a * 10, cache!(s, test_func_1(a, #[cache_ignore] &mut counter)));
+        assert_eq!(counter, 2);
+
+        // So are tampered values
+        let mut inner = s.into_inner();
+        inner.set("pc_tampered", &[0; 40]).unwrap();
+        let mut s = EncryptedStorage::new(inner, &[2; 32]);
+        assert!(s.get("pc_tampered").unwrap().is_empty());
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_tiered_storage() {
+        use storage::tiered::Policy;
+        use storage::{MemoryStorage, TieredStorage};
+
+        let a: i64 = 6;
+        let mut counter: i64 = 0;
+        let mut file = FileStorage::new("file_test").unwrap();
+        file.flush().unwrap();
+        {
+            let l2 = FileStorage::new("file_test").unwrap();
+            let mut s = TieredStorage::new(MemoryStorage::new(), l2, Policy::WriteBack);
+            assert_eq!(a * 10, cache!(s, test_func_1(a, #[cache_ignore] &mut counter)));
+            assert_eq!(a * 10, cache!(s, test_func_1(a, #[cache_ignore] &mut counter)));
+            assert_eq!(counter, 1);
+            // Not yet written to L2
+            assert_eq!(a * 10, cache!(file, test_func_1(a, #[cache_ignore] &mut counter)));
+            assert_eq!(counter, 2);
+            file.flush().unwrap();
+            s.sync().unwrap();
+        }
+        // Values from L2 are found and promoted
+        let mut l1 = MemoryStorage::new();
+        {
+            let l2 = FileStorage::new("file_test").unwrap();
+            let mut s = TieredStorage::new(&mut l1, l2, Policy::ReadThrough);
+            assert_eq!(a * 10, cache!(s, test_func_1(a, #[cache_ignore] &mut counter)));
+            assert_eq!(counter, 2);
+        }
+        file.flush().unwrap();
+        assert_eq!(a * 10, cache!(l1, test_func_1(a, #[cache_ignore] &mut counter)));
+        assert_eq!(counter, 2);
+    }
+
+    #[test]
+    fn test_write_behind_storage() {
+        use storage::WriteBehindStorage;
+
+        let a: i64 = 6;
+        let mut counter: i64 = 0;
+        let s = FileStorage::new("file_test").unwrap();
+        let mut s = WriteBehindStorage::new(s, 2);
+        s.flush().unwrap();
+        for _ in 0..3 {
+            assert_eq!(a * 10, cache!(s, test_func_1(a, #[cache_ignore] &mut counter)));
+            assert_eq!(
+                vec![1, a],
+                cache!(s, test_func_3(&[a, 1], #[cache_ignore] &mut counter))
+            );
+        }
+        assert_eq!(counter, 2);
+        s.sync().unwrap();
+        drop(s);
+
+        let mut s = FileStorage::new("file_test").unwrap();
+        assert_eq!(a * 10, cache!(s, test_func_1(a, #[cache_ignore] &mut counter)));
+        assert_eq!(counter, 2);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_cache_map() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        fn add(a: i64, b: i64) -> i64 {
+            a + b
+        }
+
+        let params = vec![(1, 2), (3, 4)];
+        assert_eq!(vec![3, 7], cache_map!(s, add(a, b) for (a, b) in params.clone()));
+        // Shares the results with `cache!`
+        let (a, b) = (3, 4);
+        assert_eq!(7, cache!(s, add(a, b)));
+
+        // Only the misses are computed
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        fn square(a: i64) -> i64 {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            a * a
+        }
+        let a = 2;
+        assert_eq!(4, cache!(s, square(a)));
+        assert_eq!(vec![1, 4, 9], cache_map!(s, square(a) for a in 1..4));
+        assert_eq!(CALLS.load(Ordering::SeqCst), 3);
+        assert_eq!(vec![9, 1], cache_map!(s, square(a) for a in [3, 1]));
+        assert_eq!(CALLS.load(Ordering::SeqCst), 3);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_metadata() {
+        let a: i64 = 6;
+        let mut counter: i64 = 0;
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        assert_eq!(a * 10, cache!(s, test_func_1(a, #[cache_ignore] &mut counter)));
+        let keys = s.keys().unwrap();
+        let meta = s.metadata(&keys[0]).unwrap().unwrap();
+        assert_eq!(meta.function, "test_func_1");
+        assert_eq!(meta.args, None);
+        assert_eq!(meta.size, s.get(&keys[0]).unwrap().len() as u64);
+        assert!(meta.compute_secs >= 0.0);
+        assert!(meta.created <= std::time::SystemTime::now());
+        // Values without metadata have none
+        s.set("pc_raw", b"raw").unwrap();
+        assert_eq!(s.metadata("pc_raw").unwrap(), None);
+        assert_eq!(s.metadata("pc_missing").unwrap(), None);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_record_args() {
+        use storage::MemoryStorage;
+
+        #[derive(Hash)]
+        struct Opaque(u8);
+        fn with_opaque(a: i64, _b: &Opaque) -> i64 {
+            a
+        }
+        fn recorded<S: PersistentCache>(s: &mut S) -> Vec<Option<String>> {
+            let mut args: Vec<_> = s
+                .keys()
+                .unwrap()
+                .iter()
+                .map(|name| s.metadata(name).unwrap().unwrap().args)
+                .collect();
+            args.sort();
+            args
+        }
+
+        let a: i64 = 6;
+        let mut counter: i64 = 0;
+        let mut s = MemoryStorage::new();
+        cache!(s, test_func_1(a, #[cache_ignore] &mut counter), record_args = true);
+        cache!(s, with_opaque(a, &Opaque(1)), record_args = true);
+        cache!(s, test_func_1(a * 10, #[cache_ignore] &mut counter));
+        let expected = vec![None, Some("6".to_string()), Some("6, _".to_string())];
+        assert_eq!(recorded(&mut s), expected);
+
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        cache_func!(
+            File,
+            "file_test",
+            [record_args = true],
+            fn mul_ten(n: i64, #[cache_ignore] counter: &mut i64) -> i64 {
+                *counter += 1;
+                n * 10
+            }
+        );
+        assert_eq!(60, mul_ten(6, &mut counter));
+        assert_eq!(recorded(&mut s), vec![Some("6".to_string())]);
+        s.flush().unwrap();
+
+        let mut s = MemoryStorage::new();
+        let params = vec![(1, 2), (3, 4)];
+        let add = |a: i64, b: i64| a + b;
+        cache_map!(s, add(a, b) for (a, b) in params, record_args = true);
+        let expected = vec![Some("1, 2".to_string()), Some("3, 4".to_string())];
+        assert_eq!(recorded(&mut s), expected);
+    }
+
+    #[test]
+    fn test_min_duration_max_size() {
+        use std::time::Duration;
+        use storage::MemoryStorage;
+
+        let a: i64 = 6;
+        let mut counter: i64 = 0;
+        let mut s = MemoryStorage::new();
+        let min = Duration::from_secs(60);
+        let f = |s: &mut MemoryStorage, counter: &mut i64| {
+            cache!(s, test_func_1(a, #[cache_ignore] counter), min_duration = min)
+        };
+        // Too cheap to be stored, hence computed on every call
+        assert_eq!(a * 10, f(&mut s, &mut counter));
+        assert_eq!(a * 10, f(&mut s, &mut counter));
+        assert_eq!(counter, 2);
+        assert!(s.keys().unwrap().is_empty());
+
+        let mut s = MemoryStorage::new();
+        cache!(s, test_func_1(a, #[cache_ignore] &mut counter), max_size = 1);
+        assert!(s.keys().unwrap().is_empty());
+        cache!(s, test_func_1(a, #[cache_ignore] &mut counter), max_size = 1 << 20);
+        cache!(s, test_func_1(a, #[cache_ignore] &mut counter), max_size = 1 << 20);
+        assert_eq!(counter, 4);
+        assert_eq!(s.keys().unwrap().len(), 1);
+
+        let mut s = MemoryStorage::new();
+        let params = vec![1, 2];
+        let double = |a: i64| a * 2;
+        let results: Vec<i64> = cache_map!(s, double(a) for a in params, max_size = 1);
+        assert_eq!(results, vec![2, 4]);
+        assert!(s.keys().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cache_if() {
+        use storage::MemoryStorage;
+
+        let mut counter: i64 = 0;
+        let mut s = MemoryStorage::new();
+        let sqrt = |a: f64| a.sqrt();
+        // The result is NaN and therefore computed again
+        assert!(cache!(s, sqrt(-1.0), cache_if = |r| !r.is_nan()).is_nan());
+        assert!(s.keys().unwrap().is_empty());
+        assert_eq!(cache!(s, sqrt(4.0), cache_if = |r| !r.is_nan()), 2.0);
+        assert_eq!(s.keys().unwrap().len(), 1);
+
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        cache_func!(
+            File,
+            "file_test",
+            [cache_if = |r: &i64| *r > 0, version = 1],
+            fn mul_ten_positive(n: i64, #[cache_ignore] counter: &mut i64) -> i64 {
+                *counter += 1;
+                n * 10
+            }
+        );
+        mul_ten_positive(-1, &mut counter);
+        mul_ten_positive(-1, &mut counter);
+        mul_ten_positive(1, &mut counter);
+        mul_ten_positive(1, &mut counter);
+        assert_eq!(counter, 3);
+        s.flush().unwrap();
+
+        let mut s = MemoryStorage::new();
+        let params = vec![-1, 1];
+        let double = |a: i64| a * 2;
+        let results: Vec<i64> = cache_map!(s, double(a) for a in params, cache_if = |r| *r > 0);
+        assert_eq!(results, vec![-2, 2]);
+        assert_eq!(s.keys().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_mode() {
+        use mode::Mode;
+
+        assert_eq!("off".parse::<Mode>().unwrap(), Mode::Off);
+        assert_eq!("ReadOnly".parse::<Mode>().unwrap(), Mode::ReadOnly);
+        assert_eq!(" refresh\n".parse::<Mode>().unwrap(), Mode::Refresh);
+        assert!("sometimes".parse::<Mode>().is_err());
+        for &m in &[Mode::Normal, Mode::Off, Mode::ReadOnly, Mode::Refresh] {
+            assert_eq!(m.to_string().parse::<Mode>().unwrap(), m);
+        }
+        assert!(Mode::ReadOnly.reads() && !Mode::ReadOnly.writes());
+        assert!(!Mode::Refresh.reads() && Mode::Refresh.writes());
+        assert!(!Mode::Off.reads() && !Mode::Off.writes());
+    }
+
+    #[test]
+    fn test_config() {
+        use config::{dir, Config, DIR_VAR};
+
+        let config =
+            Config::parse("# comment\n\ndir = \"/scratch/cache\"\nredis_url=redis://host\n");
+        assert_eq!(
+            config.unwrap(),
+            Config {
+                dir: Some("/scratch/cache".to_string()),
+                redis_url: Some("redis://host".to_string()),
+            }
+        );
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert!(Config::parse("dir").is_err());
+        assert!(Config::parse("colour = blue").is_err());
+
+        // The location is evaluated at runtime...
+        let mut counter: i64 = 0;
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        cache_func!(
+            File,
+            &["file", "test"].join("_"),
+            fn mul_ten_runtime(n: i64, #[cache_ignore] counter: &mut i64) -> i64 {
+                *counter += 1;
+                n * 10
+            }
+        );
+        assert_eq!(60, mul_ten_runtime(6, &mut counter));
+        assert_eq!(s.keys().unwrap().len(), 1);
+
+        // ... or taken from the environment
+        std::env::set_var(DIR_VAR, "file_test");
+        assert_eq!(dir(), "file_test");
+        cache_func!(
+            File,
+            fn mul_ten_config(n: i64, #[cache_ignore] counter: &mut i64) -> i64 {
+                *counter += 1;
+                n * 10
+            }
+        );
+        assert_eq!(60, mul_ten_config(6, &mut counter));
+        assert_eq!(s.keys().unwrap().len(), 2);
+        std::env::remove_var(DIR_VAR);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_registry() {
+        use storage::MemoryStorage;
+
+        assert!(registry::storage("test_missing").is_err());
+        registry::register("test_registry", MemoryStorage::new());
+        let mut counter: i64 = 0;
+        cache_func!(
+            Named,
+            "test_registry",
+            fn mul_ten_named(n: i64, #[cache_ignore] counter: &mut i64) -> i64 {
+                *counter += 1;
+                n * 10
+            }
+        );
+        cache_func!(
+            Named,
+            "test_registry",
+            "prefix",
+            [version = 1],
+            fn add_one_named(n: i64) -> i64 {
+                n + 1
+            }
+        );
+        assert_eq!(60, mul_ten_named(6, &mut counter));
+        assert_eq!(60, mul_ten_named(6, &mut counter));
+        assert_eq!(7, add_one_named(6));
+        assert_eq!(counter, 1);
+        // Both functions share the registered storage
+        let s = registry::storage("test_registry").unwrap();
+        assert_eq!(s.lock().unwrap().keys().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_cache_paths_methods_closures() {
+        use key::split_name;
+        use storage::MemoryStorage;
+
+        mod ops {
+            pub fn triple(a: i64) -> i64 {
+                a * 3
+            }
+        }
+        struct Model {
+            id: u64,
+            calls: i64,
+        }
+        impl Model {
+            fn scale(&mut self, a: i64) -> i64 {
+                self.calls += 1;
+                a * self.id as i64
+            }
+        }
+        struct Sim {
+            model: Model,
+        }
+        fn functions<S: PersistentCache>(s: &mut S) -> Vec<String> {
+            let mut functions: Vec<_> = s
+                .keys()
+                .unwrap()
+                .iter()
+                .map(|name| split_name(name).unwrap().1.to_string())
+                .collect();
+            functions.sort();
+            functions
+        }
+
+        let mut s = MemoryStorage::new();
+        assert_eq!(cache!(s, ops::triple(2)), 6);
+        assert_eq!(cache!(s, std::cmp::max(2, 6), "prefix"), 6);
+
+        let mut m = Model { id: 2, calls: 0 };
+        assert_eq!(cache!(s, m.scale(3), key = |a| (2, *a)), 6);
+        assert_eq!(cache!(s, m.scale(3), key = |a| (2, *a)), 6);
+        assert_eq!(m.calls, 1);
+        let mut sim = Sim { model: m };
+        assert_eq!(cache!(s, sim.model.scale(4), "prefix", key = |a| (2, *a)), 8);
+        assert_eq!(cache!(s, (sim.model).scale(4), "prefix", key = |a| (2, *a)), 8);
+        assert_eq!(sim.model.calls, 2);
+
+        let (a, b) = (vec![1, 2], 3);
+        let mut calls = 0;
+        let mut sum = || {
+            calls += 1;
+            a.iter().sum::<i64>() + b
+        };
+        assert_eq!(cache!(s, "sum", [a, b], &mut sum), 6);
+        assert_eq!(cache!(s, "sum", [a, b], || a.iter().sum::<i64>() + b), 6);
+        assert_eq!(calls, 1);
+
+        let expected = vec!["ops.triple", "scale", "scale", "std.cmp.max", "sum"];
+        assert_eq!(functions(&mut s), expected);
+    }
+
+    #[test]
+    fn test_storage_expressions() {
+        use std::collections::HashMap;
+        use storage::MemoryStorage;
+
+        struct Plugin {
+            cache: Box<dyn PersistentCache>,
+        }
+        impl Plugin {
+            fn run(&mut self, a: i64, counter: &mut i64) -> i64 {
+                cache!(self.cache, test_func_1(a, #[cache_ignore] counter))
+            }
+        }
+        fn dynamic(s: &mut dyn PersistentCache, a: i64, counter: &mut i64) -> i64 {
+            cache!(s, test_func_1(a, #[cache_ignore] counter))
+        }
+
+        let mut counter: i64 = 0;
+        let mut plugin = Plugin {
+            cache: Box::new(MemoryStorage::new()),
+        };
+        assert_eq!(plugin.run(6, &mut counter), 60);
+        assert_eq!(plugin.run(6, &mut counter), 60);
+        assert_eq!(dynamic(&mut *plugin.cache, 6, &mut counter), 60);
+        assert_eq!(counter, 1);
+
+        let mut storages: HashMap<&str, MemoryStorage> = HashMap::new();
+        storages.insert("x", MemoryStorage::new());
+        let params = vec![1, 2];
+        let double = |a: i64| a * 2;
+        let results: Vec<i64> =
+            cache_map!(storages.get_mut("x").unwrap(), double(a) for a in params);
+        assert_eq!(results, vec![2, 4]);
+        assert_eq!(storages.get_mut("x").unwrap().keys().unwrap().len(), 2);
+
+        registry::register("test_storage_expressions", MemoryStorage::new());
+        let shared = registry::storage("test_storage_expressions").unwrap();
+        {
+            let mut guard = shared.lock().unwrap();
+            assert_eq!(cache!(&mut *guard, test_func_1(7, #[cache_ignore] &mut counter)), 70);
+            assert_eq!(guard.keys().unwrap().len(), 1);
+        }
+
+        // Shared references to storages behind a mutex
+        let storages: HashMap<&str, Mutex<Box<dyn PersistentCache + Send>>> =
+            vec![("y", Mutex::new(Box::new(MemoryStorage::new()) as Box<_>))]
+                .into_iter()
+                .collect();
+        fn shared_ref(s: &Mutex<Box<dyn PersistentCache + Send>>, counter: &mut i64) -> i64 {
+            cache!(s, test_func_1(8, #[cache_ignore] counter))
+        }
+        assert_eq!(shared_ref(storages.get("y").unwrap(), &mut counter), 80);
+        let y = storages.get("y").unwrap();
+        assert_eq!(cache!(y, test_func_1(8_i64, #[cache_ignore] &mut counter)), 80);
+        assert_eq!(counter, 3);
+        assert_eq!(cache!(shared.clone(), test_func_1(7, #[cache_ignore] &mut counter)), 70);
+        assert_eq!(counter, 3);
+    }
+
+    #[test]
+    fn test_keys() {
+        use key::split_name;
+        use storage::MemoryStorage;
+
+        fn check<S: PersistentCache>(mut s: S, timed: bool) {
+            let a: i64 = 6;
+            let mut counter: i64 = 0;
+            s.flush().unwrap();
+            assert_eq!(a * 10, cache!(s, test_func_1(a, #[cache_ignore] &mut counter)));
+            let keys = s.keys().unwrap();
+            assert_eq!(keys.len(), 1);
+            assert_eq!(split_name(&keys[0]), Some(("DEF", "test_func_1")));
+            assert_eq!(s.modified(&keys[0]).unwrap().is_some(), timed);
+            s.delete(&keys[0]).unwrap();
+            s.delete(&keys[0]).unwrap();
+            assert!(s.keys().unwrap().is_empty());
+            assert_eq!(a * 10, cache!(s, test_func_1(a, #[cache_ignore] &mut counter)));
+            assert_eq!(counter, 2);
+            s.flush().unwrap();
+        }
+
+        check(FileStorage::new("file_test").unwrap(), true);
+        check(MemoryStorage::new(), false);
+    }
+
+    #[test]
+    #[should_panic(expected = "must not contain characters which are not allowed in file names")]
+    fn test_cache_invalid_name() {
+        key::KeyBuilder::new().name("DEF", "results/sum");
+    }
+
+    #[test]
+    #[should_panic(expected = "must not contain `_`")]
+    fn test_cache_invalid_prefix() {
+        key::KeyBuilder::new().name("my_prefix", "f");
+    }
+
+    #[test]
+    fn test_archive() {
+        use archive::{copy_between, export, import, index};
+        use std::io::Cursor;
+        use storage::MemoryStorage;
+
+        let a: i64 = 6;
+        let mut counter: i64 = 0;
+        let mut src = MemoryStorage::new();
+        assert_eq!(a * 10, cache!(src, test_func_1(a, #[cache_ignore] &mut counter)));
+        assert_eq!(a * 100, cache!(src, test_func_1(a * 10, #[cache_ignore] &mut counter)));
+        assert_eq!(counter, 2);
+
+        let mut archive = Cursor::new(vec![]);
+        assert_eq!(export(&mut src, &mut archive).unwrap(), 2);
+        let entries = index(&mut archive).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|entry| entry.modified.is_none()));
+
+        let mut dst = FileStorage::new("file_test").unwrap();
+        dst.flush().unwrap();
+        assert_eq!(import(&mut dst, &mut archive).unwrap(), 2);
+        assert_eq!(a * 10, cache!(dst, test_func_1(a, #[cache_ignore] &mut counter)));
+        assert_eq!(a * 100, cache!(dst, test_func_1(a * 10, #[cache_ignore] &mut counter)));
+        assert_eq!(counter, 2);
+
+        let mut copy = MemoryStorage::new();
+        assert_eq!(copy_between(&mut dst, &mut copy).unwrap(), 2);
+        assert_eq!(a * 10, cache!(copy, test_func_1(a, #[cache_ignore] &mut counter)));
+        assert_eq!(counter, 2);
+        dst.flush().unwrap();
+
+        assert!(import(&mut copy, Cursor::new(b"PCARCHIV".to_vec())).is_err());
+        // Corrupt sizes are rejected before anything is allocated
+        let mut bytes = archive.into_inner();
+        let footer = bytes.len() - 16;
+        let mut offset = [0; 8];
+        offset.copy_from_slice(&bytes[footer..footer + 8]);
+        let offset = u64::from_le_bytes(offset) as usize;
+        let mut corrupt = bytes.clone();
+        corrupt[offset..offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(index(&mut Cursor::new(corrupt)).is_err());
+        bytes[offset + 8..offset + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(index(&mut Cursor::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        fn stats_func(a: i64) -> i64 {
+            a + 1
+        }
+
+        let a = 1;
+        cache!(s, stats_func(a));
+        cache!(s, stats_func(a));
+        cache!(s, stats_func(a));
+        cache_map!(s, stats_func(a) for a in 1..3);
+        let report = stats();
+        let stats = &report.functions["stats_func"];
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.misses, 2);
+        assert!(stats.bytes_read > 0 && stats.bytes_written > 0);
+        assert!(report.total().hits >= 3);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_cache_ignore() {
+        let a: i64 = 6;
+        let mut counter: i64 = 0;
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        assert_eq!(
+            a * 10,
+            cache!(s, test_func_1(a, #[cache_ignore] &mut counter))
+        );
+        assert_eq!(counter, 1);
+        assert_eq!(
+            a * 10,
+            cache!(s, test_func_1(a, #[cache_ignore] &mut counter))
+        );
+        assert_eq!(counter, 1);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_key_function() {
+        let mut counter: i64 = 0;
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        // only the first argument is part of the key
+        assert_eq!(
+            12,
+            cache!(s, test_func_2(6, 2, &mut counter), key = |a, _, _| *a)
+        );
+        assert_eq!(counter, 1);
+        assert_eq!(
+            12,
+            cache!(s, test_func_2(6, 3, &mut counter), key = |a, _, _| *a)
+        );
+        assert_eq!(counter, 1);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_version() {
+        let mut counter: i64 = 0;
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        assert_eq!(
+            60,
+            cache!(s, test_func_1(6, #[cache_ignore] &mut counter), version = 1)
+        );
+        assert_eq!(
+            60,
+            cache!(s, test_func_1(6, #[cache_ignore] &mut counter), version = 1)
+        );
+        assert_eq!(counter, 1);
+        assert_eq!(
+            60,
+            cache!(s, test_func_1(6, #[cache_ignore] &mut counter), version = 2)
+        );
+        assert_eq!(counter, 2);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn test_codec() {
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        let mut c: i64 = 0;
+        let a = vec![1, 2];
+
+        let res: Vec<i64> = cache!(s, test_func_3(&a, #[cache_ignore] &mut c), codec = Codec::Json);
+        assert_eq!(res, vec![2, 1]);
+        let res: Vec<i64> = cache!(s, test_func_3(&a, #[cache_ignore] &mut c), codec = Codec::Json);
+        assert_eq!(res, vec![2, 1]);
+        assert_eq!(c, 1);
+        // The codec is recorded, such that the value can be shown without knowing its type
+        let key = &s.keys().unwrap()[0];
+        let val = s.get(key).unwrap();
+        let (_, codec, value) = entry::split(&val).unwrap();
+        assert_eq!(Codec::from_id(codec), Some(Codec::Json));
+        assert_eq!(value, b"[2,1]");
+        assert_eq!(entry::decode::<Vec<i64>>(Codec::Bincode, 0, &val), None);
+
+        // The codec of the storage is used unless the call overrides it
+        let mut s = s.with_codec(Codec::Json);
+        let res: Vec<i64> = cache!(s, test_func_3(&a, #[cache_ignore] &mut c));
+        assert_eq!(res, vec![2, 1]);
+        assert_eq!(c, 1);
+        // Values stored with another codec are not reused
+        let bincode = Codec::Bincode;
+        let res: Vec<i64> = cache!(s, test_func_3(&a, #[cache_ignore] &mut c), codec = bincode);
+        assert_eq!(res, vec![2, 1]);
+        assert_eq!(c, 2);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_schema() {
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        let mut counter: i64 = 0;
+
+        {
+            fn halve(a: i64, counter: &mut i64) -> Vec<f32> {
+                *counter += 1;
+                vec![a as f32 / 2.0]
+            }
+            assert_eq!(vec![1.5], cache!(s, halve(3, #[cache_ignore] &mut counter)));
+            assert_eq!(vec![1.5], cache!(s, halve(3, #[cache_ignore] &mut counter)));
+            assert_eq!(counter, 1);
+        }
+        {
+            // Changed return type
+            fn halve(a: i64, counter: &mut i64) -> Vec<f64> {
+                *counter += 1;
+                vec![a as f64 / 2.0]
+            }
+            assert_eq!(vec![1.5], cache!(s, halve(3, #[cache_ignore] &mut counter)));
+            assert_eq!(counter, 2);
+            assert_eq!(vec![1.5], cache!(s, halve(3, #[cache_ignore] &mut counter), schema = 1));
+            assert_eq!(counter, 3);
+            assert_eq!(vec![1.5], cache!(s, halve(3, #[cache_ignore] &mut counter), schema = 1));
+            assert_eq!(counter, 3);
+        }
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_float_arguments() {
+        let mut counter: i64 = 0;
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        assert_eq!(
+            15.0,
+            cache!(s, test_func_1(1.5, #[cache_ignore] &mut counter))
+        );
+        assert_eq!(counter, 1);
+        assert_eq!(
+            15.0,
+            cache!(s, test_func_1(1.5, #[cache_ignore] &mut counter))
+        );
+        assert_eq!(counter, 1);
+        assert_eq!(
+            vec![2.0, 1.0],
+            cache!(s, test_func_3(&vec![1.0, 2.0], #[cache_ignore] &mut counter))
+        );
+        assert_eq!(counter, 2);
+        s.flush().unwrap();
+    }
+
+    #[test]
+    fn test_hashing() {
+        // swapping the indices should change the hashes!
+        let a: i64 = 6;
+        let b: i64 = 2;
+        let mut counter: i64 = 0;
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        assert_eq!(a * b, cache!(s, test_func_2(a, b, &mut counter)));
+        assert_eq!(counter, 1);
+        let mut counter: i64 = 0;
+        assert_eq!(a * b, cache!(s, test_func_2(b, a, &mut counter)));
+        assert_eq!(counter, 1);
+    }
+
+    #[test]
+    // The macros borrow the `KeyArg` such that `ViaHash` is picked if `ViaCacheKey` does not apply
+    #[allow(clippy::needless_borrow)]
+    fn test_argument_types() {
+        // the same value with a different type results in a different key
+        let mut counter: i64 = 0;
+        let mut s = FileStorage::new("file_test").unwrap();
+        s.flush().unwrap();
+        assert_eq!(
+            60,
+            cache!(s, test_func_1(6_i64, #[cache_ignore] &mut counter))
+        );
+        assert_eq!(
+            60,
+            cache!(s, test_func_1(6_u64, #[cache_ignore] &mut counter))
+        );
+        assert_eq!(counter, 2);
+        s.flush().unwrap();
+
+        // a tuple is not the same as its elements as separate arguments
+        let mut k1 = KeyBuilder::new();
+        (&KeyArg(&(1_u8, 2_u8))).add_to(&mut k1);
+        k1.end_args();
+        let mut k2 = KeyBuilder::new();
+        (&KeyArg(&1_u8)).add_to(&mut k2);
+        (&KeyArg(&2_u8)).add_to(&mut k2);
+        k2.end_args();
+        assert_ne!(k1.name("DEF", "f"), k2.name("DEF", "f"));
+
+        // types which only implement `Hash` are added via `ViaHash`, including their type
+        #[derive(Hash)]
+        struct Id(u8);
+        let mut k3 = KeyBuilder::new();
+        (&KeyArg(&Id(1))).add_to(&mut k3);
+        k3.end_args();
+        let mut k4 = KeyBuilder::new();
+        (&KeyArg(&1_u8)).add_to(&mut k4);
+        k4.end_args();
+        assert_ne!(k3.name("DEF", "f"), k4.name("DEF", "f"));
+        let mut k5 = KeyBuilder::new();
+        (&KeyArg(&Id(1))).add_to(&mut k5);
+        k5.end_args();
+        assert_eq!(k3.name("DEF", "f"), k5.name("DEF", "f"));
     }
 
     #[test]
@@ -0,0 +1,466 @@
+// Copyright 2018 Stefan Kroboth
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # persistentcache
+//!
+//! Command line tool for inspecting and managing the entries of a `FileStorage` directory or a
+//! Redis server. Run `persistentcache --help` for the available commands.
+extern crate persistentcache;
+#[cfg(all(feature = "json", feature = "msgpack"))]
+extern crate rmp_serde;
+#[cfg(all(feature = "json", feature = "cbor"))]
+extern crate serde_cbor;
+#[cfg(feature = "json")]
+extern crate serde_json;
+
+use persistentcache::archive;
+use persistentcache::entry;
+use persistentcache::key::split_name;
+use persistentcache::storage::{FileStorage, RedisStorage};
+use persistentcache::{Codec, Metadata, PersistentCache};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::process;
+use std::time::{Duration, SystemTime};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+const USAGE: &str = "\
+Usage: persistentcache <STORAGE> <COMMAND> [OPTIONS]
+
+STORAGE is the directory of a FileStorage or the URL of a Redis server (redis://...).
+
+Commands:
+    list                List the entries with size, age and recorded arguments, by function
+    show <KEY>          Print a single entry
+    delete              Delete the entries matching the filters
+    stats               Print the number and total size of the entries per function
+    top [COUNT]         List the COUNT (default 20) entries which took longest to compute
+    export <FILE>       Write all entries into the archive FILE
+    import <FILE>       Store all entries of the archive FILE
+
+Filters (list, delete, stats, top):
+    --function <NAME>   Only entries of the function NAME
+    --prefix <PREFIX>   Only entries with the prefix PREFIX
+    --older-than <AGE>  Only entries computed more than AGE ago, e.g. 30s, 10m, 12h or 7d
+    --args <TEXT>       Only entries whose recorded arguments contain TEXT
+    --all               Required by delete if no other filter is given
+";
+
+/// Number of values fetched from the storage at once
+const BATCH: usize = 100;
+
+/// A stored entry
+struct Entry {
+    key: String,
+    prefix: String,
+    function: String,
+    size: usize,
+    age: Option<Duration>,
+    meta: Option<Metadata>,
+}
+
+/// Restricts the entries a command operates on
+#[derive(Default)]
+struct Filter {
+    function: Option<String>,
+    prefix: Option<String>,
+    older_than: Option<Duration>,
+    args: Option<String>,
+    all: bool,
+}
+
+impl Filter {
+    fn is_empty(&self) -> bool {
+        self.function.is_none()
+            && self.prefix.is_none()
+            && self.older_than.is_none()
+            && self.args.is_none()
+    }
+
+    /// Entries whose age or arguments are unknown never match a filter on them.
+    fn matches(&self, entry: &Entry) -> bool {
+        if let Some(ref function) = self.function {
+            if *function != entry.function {
+                return false;
+            }
+        }
+        if let Some(ref prefix) = self.prefix {
+            if *prefix != entry.prefix {
+                return false;
+            }
+        }
+        if let Some(ref text) = self.args {
+            match entry.meta.as_ref().and_then(|meta| meta.args.as_ref()) {
+                Some(args) if args.contains(text.as_str()) => {}
+                _ => return false,
+            }
+        }
+        match (self.older_than, entry.age) {
+            (Some(min), Some(age)) => age > min,
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() || args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        print!("{}", USAGE);
+        return;
+    }
+    if let Err(e) = run(&args) {
+        eprintln!("persistentcache: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<()> {
+    if args.len() < 2 {
+        return Err(format!("missing command\n\n{}", USAGE).into());
+    }
+    let mut storage = open(&args[0])?;
+    match args[1].as_str() {
+        "list" => list(&mut *storage, &parse_filter(&args[2..])?),
+        "show" => match args.get(2) {
+            Some(key) if args.len() == 3 => show(&mut *storage, key),
+            _ => Err("show expects exactly one key".into()),
+        },
+        "delete" => delete(&mut *storage, &parse_filter(&args[2..])?),
+        "stats" => stats(&mut *storage, &parse_filter(&args[2..])?),
+        "top" => match args.get(2).map(|count| count.parse::<usize>()) {
+            Some(Ok(count)) => top(&mut *storage, count, &parse_filter(&args[3..])?),
+            _ => top(&mut *storage, 20, &parse_filter(&args[2..])?),
+        },
+        "export" => match args.get(2) {
+            Some(file) if args.len() == 3 => {
+                let writer = BufWriter::new(File::create(file)?);
+                let count = archive::export(&mut *storage, writer)?;
+                println!("Exported {} entries", count);
+                Ok(())
+            }
+            _ => Err("export expects exactly one file".into()),
+        },
+        "import" => match args.get(2) {
+            Some(file) if args.len() == 3 => {
+                let reader = BufReader::new(File::open(file)?);
+                let count = archive::import(&mut *storage, reader)?;
+                println!("Imported {} entries", count);
+                Ok(())
+            }
+            _ => Err("import expects exactly one file".into()),
+        },
+        cmd => Err(format!("unknown command `{}`\n\n{}", cmd, USAGE).into()),
+    }
+}
+
+/// Opens the storage at `location`, a Redis URL or the directory of a `FileStorage`.
+fn open(location: &str) -> Result<Box<dyn PersistentCache>> {
+    if location.starts_with("redis://")
+        || location.starts_with("rediss://")
+        || location.starts_with("redis+unix://")
+        || location.starts_with("unix://")
+    {
+        return Ok(Box::new(RedisStorage::new(location)?));
+    }
+    let path = Path::new(location);
+    if path.is_dir() {
+        Ok(Box::new(FileStorage::new(location)?))
+    } else if path.exists() {
+        Err(format!(
+            "{} is not a directory; only FileStorage directories and Redis URLs are supported",
+            location
+        )
+        .into())
+    } else {
+        Err(format!("{} does not exist", location).into())
+    }
+}
+
+fn parse_filter(args: &[String]) -> Result<Filter> {
+    let mut filter = Filter::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("{} expects a value", arg))
+        };
+        match arg.as_str() {
+            "--function" => filter.function = Some(value()?),
+            "--prefix" => filter.prefix = Some(value()?),
+            "--older-than" => filter.older_than = Some(parse_age(&value()?)?),
+            "--args" => filter.args = Some(value()?),
+            "--all" => filter.all = true,
+            _ => return Err(format!("unknown option `{}`", arg).into()),
+        }
+    }
+    Ok(filter)
+}
+
+/// Parses ages such as `90`, `30s`, `10m`, `12h` or `7d`.
+fn parse_age(age: &str) -> Result<Duration> {
+    let (num, unit) = match age.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => age.split_at(i),
+        None => (age, "s"),
+    };
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("invalid age `{}`", age).into()),
+    };
+    match num.parse::<u64>() {
+        Ok(num) => Ok(Duration::from_secs(num * factor)),
+        Err(_) => Err(format!("invalid age `{}`", age).into()),
+    }
+}
+
+fn format_age(age: Option<Duration>) -> String {
+    let secs = match age {
+        Some(age) => age.as_secs(),
+        None => return "-".to_string(),
+    };
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 60 * 60 {
+        format!("{}m", secs / 60)
+    } else if secs < 24 * 60 * 60 {
+        format!("{}h", secs / (60 * 60))
+    } else {
+        format!("{}d", secs / (24 * 60 * 60))
+    }
+}
+
+/// Returns the entries created by the macros which match `filter`, sorted by function, prefix
+/// and key.
+fn entries(storage: &mut dyn PersistentCache, filter: &Filter) -> Result<Vec<Entry>> {
+    let now = SystemTime::now();
+    let mut keys = vec![];
+    for key in storage.keys()? {
+        if let Some((prefix, function)) =
+            split_name(&key).map(|(p, f)| (p.to_string(), f.to_string()))
+        {
+            keys.push((key, prefix, function));
+        }
+    }
+    let mut entries = vec![];
+    for chunk in keys.chunks(BATCH) {
+        let names: Vec<&str> = chunk.iter().map(|entry| entry.0.as_str()).collect();
+        let vals = storage.get_many(&names)?;
+        for ((key, prefix, function), val) in chunk.iter().zip(vals) {
+            // The entry may have been deleted in the meantime
+            if val.is_empty() {
+                continue;
+            }
+            // Entries written before metadata was stored only have a modification time
+            let meta = entry::metadata(&val);
+            let created = match meta {
+                Some(ref meta) => Some(meta.created),
+                None => storage.modified(key)?,
+            };
+            let entry = Entry {
+                key: key.clone(),
+                prefix: prefix.clone(),
+                function: function.clone(),
+                size: val.len(),
+                age: created.map(|time| now.duration_since(time).unwrap_or_default()),
+                meta,
+            };
+            if filter.matches(&entry) {
+                entries.push(entry);
+            }
+        }
+    }
+    entries.sort_by(|a, b| (&a.function, &a.prefix, &a.key).cmp(&(&b.function, &b.prefix, &b.key)));
+    Ok(entries)
+}
+
+fn list(storage: &mut dyn PersistentCache, filter: &Filter) -> Result<()> {
+    let mut current = None;
+    for entry in entries(storage, filter)? {
+        let group = (entry.function.clone(), entry.prefix.clone());
+        if current.as_ref() != Some(&group) {
+            println!("{} (prefix {})", entry.function, entry.prefix);
+            current = Some(group);
+        }
+        let args = entry.meta.as_ref().and_then(|meta| meta.args.as_ref());
+        println!(
+            "    {:<40} {:>10} B {:>5}  {}",
+            entry.key,
+            entry.size,
+            format_age(entry.age),
+            args.map_or("", |args| args.as_str())
+        );
+    }
+    Ok(())
+}
+
+/// Returns the value encoded in `bytes` as JSON without knowing its type. Returns `None` for
+/// `Bincode` and if `bytes` cannot be decoded.
+#[cfg(feature = "json")]
+fn to_json(codec: Codec, bytes: &[u8]) -> Option<String> {
+    let value: serde_json::Value = match codec {
+        Codec::Json => serde_json::from_slice(bytes).ok()?,
+        #[cfg(feature = "msgpack")]
+        Codec::MessagePack => rmp_serde::from_slice(bytes).ok()?,
+        #[cfg(feature = "cbor")]
+        Codec::Cbor => serde_cbor::from_slice(bytes).ok()?,
+        _ => return None,
+    };
+    Some(value.to_string())
+}
+
+/// Values can only be shown as JSON with the feature `json`.
+#[cfg(not(feature = "json"))]
+fn to_json(_: Codec, _: &[u8]) -> Option<String> {
+    None
+}
+
+/// Prints the entry `key`. Values encoded with a self-describing codec are printed as JSON, others
+/// as text if they are printable UTF-8 and as hex dump otherwise.
+fn show(storage: &mut dyn PersistentCache, key: &str) -> Result<()> {
+    let now = SystemTime::now();
+    let age = |time: SystemTime| now.duration_since(time).unwrap_or_default();
+    let meta = storage.metadata(key)?;
+    let val = storage.get(key)?;
+    if val.is_empty() {
+        return Err(format!("{} is not stored", key).into());
+    }
+    println!("key:         {}", key);
+    if let Some((prefix, function)) = split_name(key) {
+        println!("function:    {}", function);
+        println!("prefix:      {}", prefix);
+    }
+    println!("size:        {} B", val.len());
+    match meta {
+        Some(meta) => {
+            println!("age:         {}", format_age(Some(age(meta.created))));
+            println!("accessed:    {}", format_age(meta.accessed.map(age)));
+            println!("computed in: {:.3} s", meta.compute_secs);
+            if let Some(args) = meta.args {
+                println!("arguments:   {}", args);
+            }
+        }
+        None => println!(
+            "age:         {}",
+            format_age(storage.modified(key)?.map(age))
+        ),
+    }
+    let (fingerprint, codec, value) = match entry::split(&val) {
+        Some(parts) => parts,
+        None => return Err(format!("{} is too short to be an entry", key).into()),
+    };
+    println!("fingerprint: {:016x}", fingerprint);
+    let codec = Codec::from_id(codec).ok_or(codec);
+    match codec {
+        Ok(codec) => println!("codec:       {}", codec.name()),
+        Err(id) => println!("codec:       unknown ({})", id),
+    }
+    if let Some(json) = codec.ok().and_then(|codec| to_json(codec, value)) {
+        println!("value:       {}", json);
+        return Ok(());
+    }
+    match std::str::from_utf8(value) {
+        Ok(text) if !text.chars().any(|c| c.is_control() && !c.is_whitespace()) => {
+            println!("value:       {}", text)
+        }
+        _ => {
+            println!("value:");
+            for (i, line) in value.chunks(16).enumerate() {
+                let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+                println!("    {:08x}  {}", i * 16, hex.join(" "));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints the `count` entries which took longest to compute, i.e. which save the most time when
+/// they are fetched.
+fn top(storage: &mut dyn PersistentCache, count: usize, filter: &Filter) -> Result<()> {
+    let mut entries: Vec<(Entry, f64)> = entries(storage, filter)?
+        .into_iter()
+        .filter_map(|entry| {
+            let secs = entry.meta.as_ref()?.compute_secs;
+            Some((entry, secs))
+        })
+        .collect();
+    entries.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    println!(
+        "{:<40} {:>12} {:>12} {:>8} {:>8}",
+        "key", "compute s", "bytes", "age", "accessed"
+    );
+    let now = SystemTime::now();
+    for (entry, secs) in entries.into_iter().take(count) {
+        let accessed = storage
+            .accessed(&entry.key)?
+            .map(|time| now.duration_since(time).unwrap_or_default());
+        println!(
+            "{:<40} {:>12.3} {:>12} {:>8} {:>8}",
+            entry.key,
+            secs,
+            entry.size,
+            format_age(entry.age),
+            format_age(accessed)
+        );
+    }
+    Ok(())
+}
+
+fn delete(storage: &mut dyn PersistentCache, filter: &Filter) -> Result<()> {
+    if filter.is_empty() && !filter.all {
+        return Err("refusing to delete all entries, pass --all to do so".into());
+    }
+    let entries = entries(storage, filter)?;
+    for entry in &entries {
+        storage.delete(&entry.key)?;
+    }
+    println!("Deleted {} entries", entries.len());
+    Ok(())
+}
+
+/// Prints the number, total size and maximum age of the stored entries per function. The hits
+/// and misses recorded by `persistentcache::stats()` only cover a single process and are not
+/// available here.
+fn stats(storage: &mut dyn PersistentCache, filter: &Filter) -> Result<()> {
+    let mut groups: BTreeMap<(String, String), (usize, usize, Option<Duration>)> = BTreeMap::new();
+    for entry in entries(storage, filter)? {
+        let group = groups
+            .entry((entry.function, entry.prefix))
+            .or_insert((0, 0, None));
+        group.0 += 1;
+        group.1 += entry.size;
+        group.2 = group.2.max(entry.age);
+    }
+    println!(
+        "{:<30} {:<8} {:>8} {:>12} {:>8}",
+        "function", "prefix", "entries", "bytes", "oldest"
+    );
+    let (mut count, mut size) = (0, 0);
+    for ((function, prefix), &(n, bytes, age)) in &groups {
+        println!(
+            "{:<30} {:<8} {:>8} {:>12} {:>8}",
+            function,
+            prefix,
+            n,
+            bytes,
+            format_age(age)
+        );
+        count += n;
+        size += bytes;
+    }
+    println!("{:<30} {:<8} {:>8} {:>12}", "total", "", count, size);
+    Ok(())
+}
@@ -0,0 +1,163 @@
+// Copyright 2018 Stefan Kroboth
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # Cache modes
+//!
+//! The mode decides for the whole process whether the macros read from and write to the
+//! storages. It is taken from the environment variable `PERSISTENTCACHE_MODE` (`normal`, `off`,
+//! `readonly` or `refresh`) unless it is set with `set_mode`. This allows to bypass the cache or to
+//! recompute all results without recompiling.
+//!
+//! # Example
+//!
+//! ```
+//! #[macro_use]
+//! extern crate lazy_static;
+//! #[macro_use]
+//! extern crate persistentcache;
+//! extern crate persistentcache_procmacro;
+//! use persistentcache::mode::mode;
+//! use persistentcache::storage::memory::MemoryStorage;
+//! use persistentcache::storage::RedisStorage;
+//! use persistentcache::*;
+//! use persistentcache_procmacro::persistent_cache;
+//!
+//! fn add_two(a: u64) -> u64 {
+//!     a + 2
+//! }
+//!
+//! // No Redis server listens on this port
+//! cache_func!(Redis, "redis://127.0.0.1:1",
+//! fn add_three(a: u64) -> u64 {
+//!     a + 3
+//! });
+//!
+//! #[persistent_cache]
+//! #[params(RedisStorage, "redis://127.0.0.1:1")]
+//! fn add_four(a: u64) -> u64 {
+//!     a + 4
+//! }
+//!
+//! fn main() {
+//!     let mut s = MemoryStorage::new();
+//!     // Neither fetched nor stored
+//!     set_mode(Mode::Off);
+//!     assert_eq!(cache!(s, add_two(2)), 4);
+//!     assert!(s.keys().unwrap().is_empty());
+//!     // Storages of cached functions are not even created
+//!     assert_eq!(add_three(2), 5);
+//!     assert_eq!(add_four(2), 6);
+//!     // Fetched but not stored
+//!     set_mode(Mode::ReadOnly);
+//!     assert_eq!(cache!(s, add_two(2)), 4);
+//!     assert!(s.keys().unwrap().is_empty());
+//!     // Computed and stored even if it is cached already
+//!     set_mode(Mode::Refresh);
+//!     assert_eq!(cache!(s, add_two(2)), 4);
+//!     assert_eq!(s.keys().unwrap().len(), 1);
+//!     assert_eq!(mode(), Mode::Refresh);
+//! }
+//! ```
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use errors::*;
+use warn;
+
+/// Name of the environment variable holding the mode
+pub const MODE_VAR: &str = "PERSISTENTCACHE_MODE";
+
+/// Mode set with `set_mode`, 0 if the mode of the environment applies
+static MODE: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref ENV_MODE: Mode = match env::var(MODE_VAR) {
+        Ok(value) => value.parse().unwrap_or_else(|e: Error| {
+            warn(&format!("{}, caching normally", e));
+            Mode::Normal
+        }),
+        Err(_) => Mode::Normal,
+    };
+}
+
+/// Whether the macros read from and write to the storages
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Cached results are fetched, computed ones are stored
+    Normal,
+    /// The storages are not accessed at all, every result is computed
+    Off,
+    /// Cached results are fetched, computed ones are not stored
+    ReadOnly,
+    /// Cached results are ignored, every result is computed and stored
+    Refresh,
+}
+
+impl Mode {
+    /// Returns whether cached results are fetched from the storages.
+    pub fn reads(self) -> bool {
+        self == Mode::Normal || self == Mode::ReadOnly
+    }
+
+    /// Returns whether computed results are stored in the storages.
+    pub fn writes(self) -> bool {
+        self == Mode::Normal || self == Mode::Refresh
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Mode::Normal => 1,
+            Mode::Off => 2,
+            Mode::ReadOnly => 3,
+            Mode::Refresh => 4,
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Mode> {
+        match s.trim().to_lowercase().as_str() {
+            "normal" | "on" => Ok(Mode::Normal),
+            "off" => Ok(Mode::Off),
+            "readonly" | "read-only" => Ok(Mode::ReadOnly),
+            "refresh" => Ok(Mode::Refresh),
+            _ => bail!("Unknown cache mode `{}`", s),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Mode::Normal => "normal",
+            Mode::Off => "off",
+            Mode::ReadOnly => "readonly",
+            Mode::Refresh => "refresh",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Returns the current mode, see the module documentation.
+pub fn mode() -> Mode {
+    match MODE.load(Ordering::SeqCst) {
+        1 => Mode::Normal,
+        2 => Mode::Off,
+        3 => Mode::ReadOnly,
+        4 => Mode::Refresh,
+        _ => *ENV_MODE,
+    }
+}
+
+/// Sets the mode of the whole process, overriding the environment variable `PERSISTENTCACHE_MODE`.
+pub fn set_mode(mode: Mode) {
+    MODE.store(mode.index(), Ordering::SeqCst);
+}
@@ -0,0 +1,129 @@
+// Copyright 2018 Stefan Kroboth
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # Options
+//!
+//! Options of cached functions and function calls. In the macros, an option `name = value` calls
+//! the method `name` of `CacheOptions` with `value` as argument. The options `key` and `cache_if`
+//! receive closures and are handled by the macros themselves.
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+use Codec;
+
+/// Options of a cached function or function call
+#[derive(Clone, Debug, Default)]
+pub struct CacheOptions {
+    /// Version of the function
+    version: Option<u64>,
+    /// Codec overriding the one of the storage
+    codec: Option<Codec>,
+    /// Schema version of the return type
+    schema: u64,
+    /// Whether to store the `Debug` representation of the arguments
+    record_args: bool,
+    /// Minimum computation time of results which are stored
+    min_duration: Option<Duration>,
+    /// Maximum size in bytes of stored results
+    max_size: Option<usize>,
+}
+
+impl CacheOptions {
+    /// Returns the default options.
+    pub fn new() -> Self {
+        CacheOptions::default()
+    }
+
+    /// Sets the version of the function. Results which were cached with another version are not
+    /// used. Bump it whenever a change of the function affects its results.
+    pub fn version(mut self, version: u64) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    /// Sets the codec of the cached values, overriding the codec of the storage.
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Sets the schema version of the return type. Stored values with another schema version are
+    /// computed again and overwritten, see the `entry` module.
+    pub fn schema(mut self, schema: u64) -> Self {
+        self.schema = schema;
+        self
+    }
+
+    /// Stores the `Debug` representation of the arguments which are part of the key with every
+    /// computed result, see `Metadata::args`.
+    pub fn record_args(mut self, record: bool) -> Self {
+        self.record_args = record;
+        self
+    }
+
+    /// Only stores results whose computation took at least `duration`. Cheaper results are
+    /// computed again on every call, which is often faster than fetching them from the storage.
+    pub fn min_duration(mut self, duration: Duration) -> Self {
+        self.min_duration = Some(duration);
+        self
+    }
+
+    /// Only stores results which take at most `bytes` bytes in the storage, including the
+    /// fingerprint and metadata of the entry.
+    pub fn max_size(mut self, bytes: usize) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Returns whether a result which took `compute_secs` seconds to compute and takes `bytes`
+    /// bytes in the storage is stored.
+    #[doc(hidden)]
+    pub fn stores(&self, compute_secs: f64, bytes: usize) -> bool {
+        if let Some(min) = self.min_duration {
+            if compute_secs < min.as_secs() as f64 + f64::from(min.subsec_nanos()) * 1e-9 {
+                return false;
+            }
+        }
+        match self.max_size {
+            Some(max) => bytes <= max,
+            None => true,
+        }
+    }
+
+    /// Returns whether the arguments are recorded.
+    #[doc(hidden)]
+    pub fn records_args(&self) -> bool {
+        self.record_args
+    }
+
+    /// Returns the schema version of the return type.
+    #[doc(hidden)]
+    pub fn schema_version(&self) -> u64 {
+        self.schema
+    }
+
+    /// Returns the codec to use given the codec of the storage.
+    #[doc(hidden)]
+    pub fn codec_for(&self, storage: Codec) -> Codec {
+        self.codec.unwrap_or(storage)
+    }
+
+    /// Feeds the options which are part of the cache key into `state`.
+    #[doc(hidden)]
+    pub fn add_to_key<H: Hasher>(&self, state: &mut H) {
+        if let Some(version) = self.version {
+            version.hash(state);
+        }
+    }
+}
+
+/// Calls the predicate of the option `cache_if` with a computed result. Used by the macros, the
+/// bound lets the compiler infer the argument type of the closure.
+#[doc(hidden)]
+pub fn accepts<T: ?Sized, F: FnOnce(&T) -> bool>(result: &T, predicate: F) -> bool {
+    predicate(result)
+}
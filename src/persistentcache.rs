@@ -7,110 +7,495 @@
 
 //! Implementation of the macros `cache!` and `cache_func!`.
 use errors::*;
+use std::sync::Mutex;
 use PersistentCache;
 use PREFIX;
 
+/// Reborrows the storage passed to `cache!` and `cache_map!`, such that any expression can be
+/// passed, e.g. a variable, a field, `&mut *guard` or a `&mut dyn PersistentCache`, and is
+/// evaluated only once.
+#[doc(hidden)]
+pub trait StorageRef: PersistentCache {
+    /// Returns the storage itself.
+    fn storage_ref(&mut self) -> &mut Self {
+        self
+    }
+}
+
+impl<S: PersistentCache + ?Sized> StorageRef for S {}
+
+/// Passes shared references to storages behind a mutex on as they are, such that they can be
+/// passed to `cache!` and `cache_map!` also if they are not bound mutably. Takes precedence over
+/// `StorageRef` since it takes `self` by value.
+#[doc(hidden)]
+pub trait SharedStorageRef: Sized {
+    /// Returns the reference itself.
+    fn storage_ref(self) -> Self {
+        self
+    }
+}
+
+impl<S: PersistentCache + ?Sized> SharedStorageRef for &Mutex<S> {}
+
 /// Cache an entire function.
+///
+/// The location of the storage is an expression which is evaluated on the first call, e.g.
+/// `cache_func!(File, &std::env::var("CACHE").unwrap(), fn ...)`. Without a location, e.g.
+/// `cache_func!(File, fn ...)`, the configured one is used, see the `config` module. With
+/// `cache_func!(Named, "main", fn ...)`, the function uses the storage registered under the name
+/// `main`, see the `registry` module.
+///
+/// Arguments preceded by `#[cache_ignore]` are passed to the function as usual but do not take
+/// part in the cache key. This is useful for loggers, progress bars or counters.
+///
+/// Options are passed in brackets in front of the function, e.g.
+/// `cache_func!(File, "dir", [key = |a, b| ...], fn ...)`. Available options:
+///
+/// * `key`: Function which receives references to all arguments and returns the value which is
+///   hashed instead of the arguments. The returned value needs to implement `Hash` or `CacheKey`.
+/// * `cache_if`: Function which receives a reference to a computed result and returns whether it
+///   is stored, e.g. `cache_if = |r: &f64| r.is_finite()`. Rejected results are still returned.
+/// * Any method of `CacheOptions`, e.g. `version = 2`.
 #[macro_export]
 macro_rules! cache_func {
+    // Create `RedisStorage` at the configured URL with default prefix
+    (Redis, $([$($opt:tt)*],)? fn $f:ident($($(#[$a:ident])* $x:ident : $t:ty),*) -> $r:ty $b:block) => {
+        cache_func!(Redis, &$crate::config::redis_url(), "DEF", [$($($opt)*)?], fn $f($($(#[$a])* $x : $t),*) -> $r $b);
+    };
+    // Create `FileStorage` in the configured directory with default prefix
+    (File, $([$($opt:tt)*],)? fn $f:ident($($(#[$a:ident])* $x:ident : $t:ty),*) -> $r:ty $b:block) => {
+        cache_func!(File, &$crate::config::dir(), "DEF", [$($($opt)*)?], fn $f($($(#[$a])* $x : $t),*) -> $r $b);
+    };
     // Create `RedisStorage` with default prefix
-    (Redis, $host:expr, fn $f:ident($($x:ident : $t:ty),*) -> $r:ty $b:block) => {
-        cache_func!(Redis, $host, "DEF", fn $f($($x : $t),*) -> $r $b);
+    (Redis, $host:expr, $([$($opt:tt)*],)? fn $f:ident($($(#[$a:ident])* $x:ident : $t:ty),*) -> $r:ty $b:block) => {
+        cache_func!(Redis, $host, "DEF", [$($($opt)*)?], fn $f($($(#[$a])* $x : $t),*) -> $r $b);
     };
     // Create `FileStorage` with default prefix
-    (File, $dir:expr, fn $f:ident($($x:ident : $t:ty),*) -> $r:ty $b:block) => {
-        cache_func!(File, $dir, "DEF", fn $f($($x : $t),*) -> $r $b);
+    (File, $dir:expr, $([$($opt:tt)*],)? fn $f:ident($($(#[$a:ident])* $x:ident : $t:ty),*) -> $r:ty $b:block) => {
+        cache_func!(File, $dir, "DEF", [$($($opt)*)?], fn $f($($(#[$a])* $x : $t),*) -> $r $b);
+    };
+    // Use the storage registered under the given name with default prefix
+    (Named, $name:expr, $([$($opt:tt)*],)? fn $f:ident($($(#[$a:ident])* $x:ident : $t:ty),*) -> $r:ty $b:block) => {
+        cache_func!(Named, $name, "DEF", [$($($opt)*)?], fn $f($($(#[$a])* $x : $t),*) -> $r $b);
     };
     // Create `RedisStorage` with provided prefix
-    (Redis, $host:expr, $prefix:expr, fn $f:ident($($x:ident : $t:ty),*) -> $r:ty $b:block) => {
+    (Redis, $host:expr, $prefix:expr, $([$($opt:tt)*],)? fn $f:ident($($(#[$a:ident])* $x:ident : $t:ty),*) -> $r:ty $b:block) => {
         fn $f($($x: $t),*) -> $r {
             lazy_static!{
                 // Unfortunately, the `redis` crate requires Mutex to work.
                 // May need to look into this in more detail.
                 static ref S: ::std::sync::Mutex<::storage::redis::RedisStorage> = ::std::sync::Mutex::new(::storage::redis::RedisStorage::new($host).unwrap());
             };
-            cache_func!($f($($x),*), $b, $prefix);
+            cache_func!($f($($(#[$a])* $x),*), $b, $prefix, ::storage::redis::RedisStorage, [$($($opt)*)?]);
         }
     };
     // Create `FileStorage` with provided prefix
-    (File, $dir:expr, $prefix:expr, fn $f:ident($($x:ident : $t:ty),*) -> $r:ty $b:block) => {
+    (File, $dir:expr, $prefix:expr, $([$($opt:tt)*],)? fn $f:ident($($(#[$a:ident])* $x:ident : $t:ty),*) -> $r:ty $b:block) => {
         fn $f($($x: $t),*) -> $r {
             lazy_static!{
                 // In order to be consistent with `RedisStorage`, `FileStorage` also uses a Mutex.
                 // However, it would not be necessary.
                 static ref S: ::std::sync::Mutex<::storage::file::FileStorage> = ::std::sync::Mutex::new(::storage::file::FileStorage::new($dir).unwrap());
             };
-            cache_func!($f($($x),*), $b, $prefix);
+            cache_func!($f($($(#[$a])* $x),*), $b, $prefix, ::storage::file::FileStorage, [$($($opt)*)?]);
         }
     };
-    // internal
-    ($f:ident($($x:ident),*), $b:block, $prefix:expr) => {
-        extern crate bincode as pers_f_bincode;
-        // use bincode as pers_f_bincode;
-        // use bincode;
-        use ::std::hash::{Hash, Hasher};
-
-        let mut s = ::std::collections::hash_map::DefaultHasher::new();
-        for item in &[&($($x),*)] {
-            item.hash(&mut s);
+    // Use the storage registered under the given name with provided prefix
+    (Named, $name:expr, $prefix:expr, $([$($opt:tt)*],)? fn $f:ident($($(#[$a:ident])* $x:ident : $t:ty),*) -> $r:ty $b:block) => {
+        fn $f($($x: $t),*) -> $r {
+            lazy_static!{
+                static ref S: $crate::registry::SharedStorage = $crate::registry::storage($name).unwrap();
+            };
+            cache_func!($f($($(#[$a])* $x),*), $b, $prefix, Box<dyn $crate::PersistentCache + Send>, [$($($opt)*)?]);
         }
-        let var_name = format!("{}_{}_{}_{:?}", PREFIX, $prefix, stringify!($f), s.finish());
-        let result: Vec<u8> = S.lock().unwrap().get(&var_name).unwrap();
+    };
+    // internal
+    ($f:ident($($(#[$a:ident])* $x:ident),*), $b:block, $prefix:expr, $st:ty, [$($opt:tt)*]) => {
+        cache_func!(@opts ($f($($(#[$a])* $x),*), $b, $prefix, $st) () () [] [$($opt)*]);
+    };
+    // internal: options
+    (@opts $ctx:tt $key:tt $cond:tt $opts:tt [key = $kf:expr $(, $o:ident = $v:expr)*]) => {
+        cache_func!(@opts $ctx ($kf) $cond $opts [$($o = $v),*]);
+    };
+    (@opts $ctx:tt $key:tt $cond:tt $opts:tt [cache_if = $cf:expr $(, $o:ident = $v:expr)*]) => {
+        cache_func!(@opts $ctx $key ($cf) $opts [$($o = $v),*]);
+    };
+    (@opts $ctx:tt $key:tt $cond:tt [$($opts:tt)*] [$opt:ident = $val:expr $(, $o:ident = $v:expr)*]) => {
+        cache_func!(@opts $ctx $key $cond [$($opts)* .$opt($val)] [$($o = $v),*]);
+    };
+    (@opts ($f:ident($($(#[$a:ident])* $x:ident),*), $b:block, $prefix:expr, $st:ty) $key:tt $cond:tt [$($opts:tt)*] []) => {
+        #[allow(unused_imports)]
+        use $crate::entry::{ViaDebug, ViaNoDebug};
+        #[allow(unused_imports)]
+        use $crate::key::{ViaCacheKey, ViaHash};
 
-        match result.len() {
-            0 => {
-                let res = {$b};
-                S.lock().unwrap().set(&var_name, &pers_f_bincode::serialize(&res).unwrap()).unwrap();
+        const _: () = $crate::key::check_name($prefix, stringify!($f));
+        let mode = $crate::mode::mode();
+        let mut rec = $crate::stats::Recorder::with_storage_type(stringify!($f), ::std::any::type_name::<$st>());
+        // With caching off, the storage is not even created
+        let lookup = if mode == $crate::mode::Mode::Off {
+            None
+        } else {
+            let opts = $crate::CacheOptions::new()$($opts)*;
+            let mut key = $crate::key::KeyBuilder::new();
+            cache_func!(@key key, $key, $($(#[$a])* $x),*);
+            key.end_args();
+            opts.add_to_key(&mut key);
+            let codec = opts.codec_for(S.lock().unwrap().codec());
+            codec.add_to_key(&mut key);
+            let var_name = key.name($prefix, stringify!($f));
+            let result: Vec<u8> = if mode.reads() {
+                rec.storage(|| S.lock().unwrap().get(&var_name))
+            } else {
+                vec![]
+            };
+            if let Some(res) = $crate::entry::decode(codec, opts.schema_version(), &result) {
+                rec.hit(&var_name, result.len());
                 return res;
-            },
-            _ => return pers_f_bincode::deserialize(&result).unwrap(),
+            }
+            Some((opts, codec, var_name))
+        };
+
+        let debug_args = match lookup {
+            Some((ref opts, _, _)) if opts.records_args() => Some(cache_func!(@debug $($(#[$a])* $x),*)),
+            _ => None,
+        };
+        let res = rec.compute(|| $b);
+        match lookup {
+            Some((opts, codec, var_name)) => {
+                let entry = $crate::entry::encode(codec, opts.schema_version(), &rec.metadata(debug_args), &res).unwrap();
+                if mode.writes() && cache!(@cond $cond, &res) && opts.stores(rec.last_compute_secs(), entry.len()) {
+                    rec.storage(|| S.lock().unwrap().set(&var_name, &entry));
+                    rec.miss(&var_name, entry.len());
+                } else {
+                    rec.skip(&var_name, entry.len());
+                }
+            }
+            None => rec.bypass(),
         }
-    }
+        return res;
+    };
+    // internal: add the arguments to the key...
+    (@key $key:ident, (), $($(#[$a:ident])* $x:ident),*) => {
+        $(cache_func!(@arg $key, $(#[$a])* $x);)*
+    };
+    // ... or the value returned by the key function
+    (@key $key:ident, ($kf:expr), $($(#[$a:ident])* $x:ident),*) => {
+        (&$crate::key::KeyArg(&$crate::key::KeyFn::apply_key(($(&$x,)*), $kf))).add_to(&mut $key);
+    };
+    // internal: arguments marked with `#[cache_ignore]` are not part of the key
+    (@arg $key:ident, #[cache_ignore] $x:ident) => {};
+    (@arg $key:ident, $x:ident) => {
+        (&$crate::key::KeyArg(&$x)).add_to(&mut $key);
+    };
+    // internal: the `Debug` representation of the arguments which are part of the key
+    (@debug $($(#[$a:ident])* $x:ident),*) => {{
+        let args: Vec<Option<String>> = vec![$(cache_func!(@debug_arg $(#[$a])* $x)),*];
+        args.into_iter().flatten().collect::<Vec<_>>().join(", ")
+    }};
+    (@debug_arg #[cache_ignore] $x:ident) => {
+        None
+    };
+    (@debug_arg $x:ident) => {
+        Some((&$crate::entry::DebugArg(&$x)).debug_repr())
+    };
 }
 
 /// Cache a single function call.
+///
+/// The storage may be any expression which yields a storage or a mutable reference to one, e.g.
+/// `s`, `self.cache`, `&mut *guard` or a `&mut dyn PersistentCache`, or a shared reference to a
+/// storage behind a mutex, e.g. a `&Mutex<dyn PersistentCache + Send>`. It is evaluated once.
+///
+/// Every argument is evaluated exactly once. Besides functions, the call may be
+///
+/// * a path, e.g. `cache!(s, module::f(a))`, stored under the name `module.f`,
+/// * a method call, e.g. `cache!(s, self.model.f(a), key = |a| (self.model.id, *a))`, stored under
+///   the name of the method. Since the receiver is not part of the key, a key function is
+///   required. Receivers other than variables and fields need parentheses, e.g. `(x + y).f(a)`.
+/// * a closure without arguments, e.g. `cache!(s, "name", [a, b], || f(&a, &b))`, stored under the
+///   given name and keyed by references to the expressions in brackets. The name needs to be a
+///   string literal or a constant, i.e. a `&'static str`, and must not contain characters which
+///   are not allowed in file names, such as `/` or `:`.
+///
+/// A prefix can be passed after the call, e.g. `cache!(s, f(a), "sim")`. It needs to be a constant
+/// as well and must not contain `_`. Invalid names and prefixes are rejected at compile time.
+///
+/// Arguments preceded by `#[cache_ignore]` are passed to the function as usual but do not take
+/// part in the cache key, e.g. `cache!(s, f(a, #[cache_ignore] &mut counter))`.
+///
+/// Options can be appended to the call, e.g. `cache!(s, f(a, b), key = |a, b| ...)`. Available
+/// options:
+///
+/// * `key`: Function which receives references to all arguments and returns the value which is
+///   hashed instead of the arguments. The returned value needs to implement `Hash` or `CacheKey`.
+/// * `cache_if`: Function which receives a reference to a computed result and returns whether it
+///   is stored, e.g. `cache_if = |r: &f64| r.is_finite()`. Rejected results are still returned.
+/// * Any method of `CacheOptions`, e.g. `version = 2`.
 #[macro_export]
 macro_rules! cache {
     // no prefix provided
-    ($storage:ident, $func:ident($($x:expr),*)) => {
-        cache!($storage, $func($($x),*), "DEF")
+    ($storage:expr, $func:ident($($x:tt)*) $(, $opt:ident = $val:expr)*) => {
+        cache!($storage, $func($($x)*), "DEF" $(, $opt = $val)*)
     };
     // prefix provided
-    ($storage:ident, $func:ident($($x:expr),*), $prefix:expr) => {
+    ($storage:expr, $func:ident($($x:tt)*), $prefix:expr $(, $opt:ident = $val:expr)*) => {
+        cache!(@args ($storage, (call [$func]), stringify!($func), $prefix, [$($opt = $val),*]) [] [] $($x)*)
+    };
+    // paths, e.g. `module::f(a)`, which are stored under the whole path
+    ($storage:expr, $first:ident $(:: $rest:ident)+ ($($x:tt)*) $(, $opt:ident = $val:expr)*) => {
+        cache!($storage, $first $(:: $rest)+ ($($x)*), "DEF" $(, $opt = $val)*)
+    };
+    ($storage:expr, $first:ident $(:: $rest:ident)+ ($($x:tt)*), $prefix:expr $(, $opt:ident = $val:expr)*) => {
+        cache!(@args ($storage, (call [$first $(:: $rest)+]), concat!(stringify!($first) $(, "::", stringify!($rest))+), $prefix, [$($opt = $val),*]) [] [] $($x)*)
+    };
+    // method calls, e.g. `self.model.compute(a)`, which are stored under the name of the method
+    ($storage:expr, $recv:ident . $($rest:tt)+) => {
+        cache!(@method ($storage) [$recv] $($rest)+)
+    };
+    ($storage:expr, ($($recv:tt)*) . $($rest:tt)+) => {
+        cache!(@method ($storage) [($($recv)*)] $($rest)+)
+    };
+    // closures, e.g. `cache!(s, "name", [a, b], || f(a, b))`, which are stored under the given name
+    ($storage:expr, $name:expr, [$($x:expr),*], $closure:expr $(, $opt:ident = $val:expr)*) => {
+        cache!(@args ($storage, (closure $closure), $name, "DEF", [$($opt = $val),*]) [] [] $(&$x),*)
+    };
+    // internal: collect the receiver of a method call
+    (@method $storage:tt [$($r:tt)*] $field:ident . $($rest:tt)+) => {
+        cache!(@method $storage [$($r)* . $field] $($rest)+)
+    };
+    (@method $storage:tt [$($r:tt)*] $method:ident($($x:tt)*) $(, $opt:ident = $val:expr)*) => {
+        cache!(@method $storage [$($r)*] $method($($x)*), "DEF" $(, $opt = $val)*)
+    };
+    (@method $storage:tt [$($r:tt)*] $method:ident($($x:tt)*), $prefix:expr $(, $opt:ident = $val:expr)*) => {
+        cache!(@args ($storage, (method [$($r)* . $method]), stringify!($method), $prefix, [$($opt = $val),*]) [] [] $($x)*)
+    };
+    // internal: bind every argument to a variable `arg` (every recursion creates a new one due to
+    // hygiene) and collect the variables which are part of the key
+    (@args $ctx:tt [$($c:expr => $n:ident,)*] [$($k:ident,)*] #[cache_ignore] $x:expr, $($rest:tt)*) => {
+        cache!(@args $ctx [$($c => $n,)* $x => arg,] [$($k,)*] $($rest)*)
+    };
+    (@args $ctx:tt [$($c:expr => $n:ident,)*] [$($k:ident,)*] #[cache_ignore] $x:expr) => {
+        cache!(@args $ctx [$($c => $n,)* $x => arg,] [$($k,)*])
+    };
+    (@args $ctx:tt [$($c:expr => $n:ident,)*] [$($k:ident,)*] $x:expr, $($rest:tt)*) => {
+        cache!(@args $ctx [$($c => $n,)* $x => arg,] [$($k,)* arg,] $($rest)*)
+    };
+    (@args $ctx:tt [$($c:expr => $n:ident,)*] [$($k:ident,)*] $x:expr) => {
+        cache!(@args $ctx [$($c => $n,)* $x => arg,] [$($k,)* arg,])
+    };
+    (@args ($storage:expr, $call:tt, $name:expr, $prefix:expr, $opts:tt) $c:tt $k:tt) => {
+        cache!(@opts ($storage, $call, $name, $prefix) $c $k () () [] $opts)
+    };
+    // internal: options
+    (@opts $ctx:tt $c:tt $k:tt $key:tt $cond:tt $opts:tt [key = $kf:expr $(, $o:ident = $v:expr)*]) => {
+        cache!(@opts $ctx $c $k ($kf) $cond $opts [$($o = $v),*])
+    };
+    (@opts $ctx:tt $c:tt $k:tt $key:tt $cond:tt $opts:tt [cache_if = $cf:expr $(, $o:ident = $v:expr)*]) => {
+        cache!(@opts $ctx $c $k $key ($cf) $opts [$($o = $v),*])
+    };
+    (@opts $ctx:tt $c:tt $k:tt $key:tt $cond:tt [$($opts:tt)*] [$opt:ident = $val:expr $(, $o:ident = $v:expr)*]) => {
+        cache!(@opts $ctx $c $k $key $cond [$($opts)* .$opt($val)] [$($o = $v),*])
+    };
+    // internal: the receiver of a method call is not part of the key
+    (@opts ($storage:expr, (method $f:tt), $name:expr, $prefix:expr) $c:tt $k:tt () $cond:tt $opts:tt []) => {
+        compile_error!("Caching a method call requires a key function, e.g. `key = |a| (self.id, *a)`, since the receiver is not part of the key")
+    };
+    // internal
+    (@opts ($storage:expr, $call:tt, $name:expr, $prefix:expr) [$($c:expr => $n:ident,)*] $k:tt $key:tt $cond:tt [$($opts:tt)*] []) => {
         (||{
-            extern crate bincode as pers_bincode;
-            use ::std::hash::{Hash, Hasher};
+            #[allow(unused_imports)]
+            use $crate::entry::{ViaDebug, ViaNoDebug};
+            #[allow(unused_imports)]
+            use $crate::key::{ViaCacheKey, ViaHash};
+            #[allow(unused_imports)]
+            use $crate::persistentcache::{SharedStorageRef, StorageRef};
+
+            const _: () = $crate::key::check_name($prefix, $name);
+            let ($($n,)*) = ($($c,)*);
+            // Temporaries in the storage expression need to live until the end
+            match $storage.storage_ref() {
+                #[allow(unused_mut)]
+                mut storage => {
+                    let opts = $crate::CacheOptions::new()$($opts)*;
+                    let mut key = $crate::key::KeyBuilder::new();
+                    cache!(@key key, $key, [$($n,)*], $k);
+                    key.end_args();
+                    opts.add_to_key(&mut key);
+                    let codec = opts.codec_for(storage.codec());
+                    codec.add_to_key(&mut key);
+                    let var_name = key.name($prefix, $name);
+                    let mut rec = $crate::stats::Recorder::new($name, &*storage);
+                    let mode = $crate::mode::mode();
 
-            let mut s = ::std::collections::hash_map::DefaultHasher::new();
-            for item in &[&($($x),*)] {
-                item.hash(&mut s);
+                    let result: Vec<u8> = if mode.reads() {
+                        rec.storage(|| $crate::PersistentCache::get(&mut storage, &var_name))
+                    } else {
+                        vec![]
+                    };
+                    let res;
+                    match $crate::entry::decode(codec, opts.schema_version(), &result) {
+                        None => {
+                            let debug_args = if opts.records_args() {
+                                Some(cache!(@debug $k))
+                            } else {
+                                None
+                            };
+                            res = rec.compute(|| cache!(@call $call [$($n),*]));
+                            let entry = $crate::entry::encode(codec, opts.schema_version(), &rec.metadata(debug_args), &res).unwrap();
+                            if mode.writes() && cache!(@cond $cond, &res) && opts.stores(rec.last_compute_secs(), entry.len()) {
+                                rec.storage(|| $crate::PersistentCache::set(&mut storage, &var_name, &entry));
+                                rec.miss(&var_name, entry.len());
+                            } else {
+                                rec.skip(&var_name, entry.len());
+                            }
+                            res
+                        },
+                        Some(cached) => {
+                            rec.hit(&var_name, result.len());
+                            res = cached;
+                            res
+                        }
+                    }
+                }
             }
-            let var_name = format!("{}_{}_{}_{:?}", PREFIX, $prefix, stringify!($func), s.finish());
+        })()
+    };
+    // internal: call the function, method or closure with the arguments
+    (@call (call [$($f:tt)*]) [$($n:ident),*]) => {
+        $($f)*($($n),*)
+    };
+    (@call (method [$($f:tt)*]) [$($n:ident),*]) => {
+        $($f)*($($n),*)
+    };
+    (@call (closure $closure:expr) $n:tt) => {
+        ($closure)()
+    };
+    // internal: add the arguments to the key...
+    (@key $key:ident, (), $n:tt, [$($k:ident,)*]) => {
+        $((&$crate::key::KeyArg(&$k)).add_to(&mut $key);)*
+    };
+    // ... or the value returned by the key function
+    (@key $key:ident, ($kf:expr), [$($n:ident,)*], $k:tt) => {
+        (&$crate::key::KeyArg(&$crate::key::KeyFn::apply_key(($(&$n,)*), $kf))).add_to(&mut $key);
+    };
+    // internal: whether the result is stored according to the predicate `cache_if`
+    (@cond (), $res:expr) => {
+        true
+    };
+    (@cond ($cf:expr), $res:expr) => {
+        $crate::options::accepts($res, $cf)
+    };
+    // internal: the `Debug` representation of the arguments which are part of the key
+    (@debug [$($k:ident,)*]) => {{
+        let args: Vec<String> = vec![$((&$crate::entry::DebugArg(&$k)).debug_repr()),*];
+        args.join(", ")
+    }};
+}
 
-            let result: Vec<u8> = $storage.get(&var_name).unwrap();
-            let res;
-            match result.len() {
-                0 => {
-                    res = $func($($x),*);
-                    $storage.set(&var_name, &pers_bincode::serialize(&res).unwrap()).unwrap();
-                    res
-                    // match $func($($x),*) {
-                    //     Ok(res) => {
-                    //         $storage.set(&var_name, &pers_bincode::serialize(&res)?)?;
-                    //         Ok(res)
-                    //     }
-                    //     Err(e) => Err(e)
-                    // }
-                },
-                // _ => match pers_bincode::deserialize(&result) {
-                //     Ok(res) => res,
-                //     Err(e) => panic!(e.into()), // I have no idea what I am doing.
-                // }
-                _ => {
-                    res = pers_bincode::deserialize(&result).unwrap();
-                    res
+/// Cache the calls of a function for many arguments at once.
+///
+/// `cache_map!(s, f(a, b) for (a, b) in params)` returns a `Vec` with the results of `f(a, b)` for
+/// all items of `params`, in the same order. All cached results are fetched from the storage in one
+/// batch via `get_many`, only the missing ones are computed and those are stored in one batch via
+/// `set_many`. The results are stored under the same names as with `cache!(s, f(a, b))`.
+///
+/// The storage may be any expression, as for `cache!`. The arguments need to be plain variables
+/// bound by the pattern. A prefix and options can be
+/// appended as for `cache!`, e.g. `cache_map!(s, f(a) for a in params, "prefix", version = 2)`.
+#[macro_export]
+macro_rules! cache_map {
+    // no prefix provided
+    ($storage:expr, $func:ident($($x:ident),*) for $p:pat in $iter:expr $(, $opt:ident = $val:expr)*) => {
+        cache_map!($storage, $func($($x),*) for $p in $iter, "DEF" $(, $opt = $val)*)
+    };
+    // prefix provided
+    ($storage:expr, $func:ident($($x:ident),*) for $p:pat in $iter:expr, $prefix:expr $(, $opt:ident = $val:expr)*) => {
+        cache_map!(@opts ($storage, $func($($x),*), $p, $iter, $prefix) () () [] [$($opt = $val),*])
+    };
+    // internal: options
+    (@opts $ctx:tt $key:tt $cond:tt $opts:tt [key = $kf:expr $(, $o:ident = $v:expr)*]) => {
+        cache_map!(@opts $ctx ($kf) $cond $opts [$($o = $v),*])
+    };
+    (@opts $ctx:tt $key:tt $cond:tt $opts:tt [cache_if = $cf:expr $(, $o:ident = $v:expr)*]) => {
+        cache_map!(@opts $ctx $key ($cf) $opts [$($o = $v),*])
+    };
+    (@opts $ctx:tt $key:tt $cond:tt [$($opts:tt)*] [$opt:ident = $val:expr $(, $o:ident = $v:expr)*]) => {
+        cache_map!(@opts $ctx $key $cond [$($opts)* .$opt($val)] [$($o = $v),*])
+    };
+    // internal
+    (@opts ($storage:expr, $func:ident($($x:ident),*), $p:pat, $iter:expr, $prefix:expr) $key:tt $cond:tt [$($opts:tt)*] []) => {
+        (||{
+            #[allow(unused_imports)]
+            use $crate::entry::{ViaDebug, ViaNoDebug};
+            #[allow(unused_imports)]
+            use $crate::key::{ViaCacheKey, ViaHash};
+            #[allow(unused_imports)]
+            use $crate::persistentcache::{SharedStorageRef, StorageRef};
+
+            const _: () = $crate::key::check_name($prefix, stringify!($func));
+            // Temporaries in the storage expression need to live until the end
+            match $storage.storage_ref() {
+                #[allow(unused_mut)]
+                mut storage => {
+                    let opts = $crate::CacheOptions::new()$($opts)*;
+                    let codec = opts.codec_for(storage.codec());
+                    let mut names = vec![];
+                    let mut args = vec![];
+                    for $p in $iter {
+                        let mut key = $crate::key::KeyBuilder::new();
+                        cache!(@key key, $key, [$($x,)*], [$($x,)*]);
+                        key.end_args();
+                        opts.add_to_key(&mut key);
+                        codec.add_to_key(&mut key);
+                        names.push(key.name($prefix, stringify!($func)));
+                        args.push(($($x,)*));
+                    }
+
+                    let mut rec = $crate::stats::Recorder::new(stringify!($func), &*storage);
+                    let mode = $crate::mode::mode();
+                    let stored = if mode.reads() {
+                        let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+                        rec.storage(|| $crate::PersistentCache::get_many(&mut storage, &names))
+                    } else {
+                        vec![vec![]; names.len()]
+                    };
+                    let mut results = Vec::with_capacity(names.len());
+                    let mut entries = vec![];
+                    for ((name, ($($x,)*)), val) in names.iter().zip(args).zip(stored) {
+                        match $crate::entry::decode(codec, opts.schema_version(), &val) {
+                            Some(res) => {
+                                rec.hit(name, val.len());
+                                results.push(res);
+                            }
+                            None => {
+                                let debug_args = if opts.records_args() {
+                                    Some(cache!(@debug [$($x,)*]))
+                                } else {
+                                    None
+                                };
+                                let res = rec.compute(|| $func($($x),*));
+                                let entry = $crate::entry::encode(codec, opts.schema_version(), &rec.metadata(debug_args), &res).unwrap();
+                                if mode.writes() && cache!(@cond $cond, &res) && opts.stores(rec.last_compute_secs(), entry.len()) {
+                                    rec.miss(name, entry.len());
+                                    entries.push((name.as_str(), entry));
+                                } else {
+                                    rec.skip(name, entry.len());
+                                }
+                                results.push(res);
+                            }
+                        }
+                    }
+                    let entries: Vec<(&str, &[u8])> = entries
+                        .iter()
+                        .map(|&(name, ref entry)| (name, &entry[..]))
+                        .collect();
+                    if mode.writes() {
+                        rec.storage(|| $crate::PersistentCache::set_many(&mut storage, &entries));
+                    }
+                    results
                 }
             }
-       })()
-    }
+        })()
+    };
 }
@@ -0,0 +1,65 @@
+// Copyright 2018 Stefan Kroboth
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # Storage registry
+//!
+//! By default, every function cached with `cache_func!` or `#[persistent_cache]` creates its own
+//! storage, and hence its own connection in case of Redis. Instead, storages can be registered
+//! once under a name and shared by all functions which refer to this name:
+//!
+//! ```text
+//! #[persistent_cache(storage = "main")]
+//! fn add_two(a: u64) -> u64 { ... }
+//!
+//! cache_func!(Named, "main", fn add_three(a: u64) -> u64 { ... });
+//!
+//! fn main() {
+//!     registry::register("main", RedisStorage::new("redis://127.0.0.1").unwrap());
+//!     ...
+//! }
+//! ```
+//!
+//! A function looks up its storage on its first call, hence the storage has to be registered
+//! before. Registering another storage under the same name later does not affect functions which
+//! were called already.
+//!
+//! # Example
+//!
+//! ```
+//! use persistentcache::registry;
+//! use persistentcache::storage::memory::MemoryStorage;
+//!
+//! registry::register("main", MemoryStorage::new());
+//! let s = registry::storage("main").unwrap();
+//! s.lock().unwrap().set("pc_example", b"value").unwrap();
+//! ```
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use errors::*;
+use PersistentCache;
+
+/// Storage shared by several functions
+pub type SharedStorage = Arc<Mutex<Box<dyn PersistentCache + Send>>>;
+
+lazy_static! {
+    static ref REGISTRY: Mutex<HashMap<String, SharedStorage>> = Mutex::new(HashMap::new());
+}
+
+/// Registers `storage` under `name`, replacing the storage registered under this name before.
+pub fn register<S: PersistentCache + Send + 'static>(name: &str, storage: S) {
+    let storage: SharedStorage = Arc::new(Mutex::new(Box::new(storage)));
+    REGISTRY.lock().unwrap().insert(name.to_string(), storage);
+}
+
+/// Returns the storage registered under `name`.
+pub fn storage(name: &str) -> Result<SharedStorage> {
+    match REGISTRY.lock().unwrap().get(name) {
+        Some(storage) => Ok(storage.clone()),
+        None => bail!("No storage registered under the name `{}`", name),
+    }
+}
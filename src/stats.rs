@@ -0,0 +1,220 @@
+// Copyright 2018 Stefan Kroboth
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # Statistics
+//!
+//! All macros record per function name how often results were found in the storage (hits) or
+//! had to be computed (misses), how many bytes were read from and written to the storage and how
+//! much time was spent in the storage and computing. The statistics cover the current process.
+//!
+//! The saved time is estimated as the number of hits times the average time of computing a
+//! result. It is only known for functions which were computed at least once in this process.
+//!
+//! # Example
+//!
+//! ```
+//! let report = persistentcache::stats::stats();
+//! for (function, stats) in &report.functions {
+//!     println!("{}: {} hits, {} misses", function, stats.hits, stats.misses);
+//! }
+//! ```
+use entry::Metadata;
+use errors::*;
+use serde::{Deserialize, Serialize};
+use std::any::type_name;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+#[cfg(feature = "tracing")]
+use tracing::{debug, debug_span, error, trace};
+
+lazy_static! {
+    static ref STATS: Mutex<BTreeMap<String, FunctionStats>> = Mutex::new(BTreeMap::new());
+}
+
+/// Statistics of a single function
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FunctionStats {
+    /// Number of results fetched from the storage
+    pub hits: u64,
+    /// Number of results computed
+    pub misses: u64,
+    /// Bytes read from the storage
+    pub bytes_read: u64,
+    /// Bytes written to the storage
+    pub bytes_written: u64,
+    /// Seconds spent reading from and writing to the storage
+    pub storage_secs: f64,
+    /// Seconds spent computing results
+    pub compute_secs: f64,
+    /// Estimated seconds saved by fetching results instead of computing them
+    pub saved_secs: f64,
+}
+
+impl FunctionStats {
+    fn add(&mut self, other: &FunctionStats) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+        self.storage_secs += other.storage_secs;
+        self.compute_secs += other.compute_secs;
+    }
+}
+
+/// Statistics of all functions, see `stats()`
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StatsReport {
+    /// Statistics per function name
+    pub functions: BTreeMap<String, FunctionStats>,
+}
+
+impl StatsReport {
+    /// Returns the sum of the statistics of all functions.
+    pub fn total(&self) -> FunctionStats {
+        let mut total = FunctionStats::default();
+        for stats in self.functions.values() {
+            total.add(stats);
+            total.saved_secs += stats.saved_secs;
+        }
+        total
+    }
+}
+
+/// Returns the statistics of all functions cached so far.
+pub fn stats() -> StatsReport {
+    let mut functions = STATS.lock().unwrap().clone();
+    for stats in functions.values_mut() {
+        if stats.misses > 0 {
+            stats.saved_secs = stats.hits as f64 * stats.compute_secs / stats.misses as f64;
+        }
+    }
+    StatsReport { functions }
+}
+
+/// Resets the statistics of all functions.
+pub fn reset_stats() {
+    STATS.lock().unwrap().clear();
+}
+
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) * 1e-9
+}
+
+/// Records the statistics of a cached call. Used by the macros, the statistics are added to the
+/// global ones when it is dropped.
+///
+/// With the feature `tracing`, the call is wrapped in a `persistentcache` span and every storage
+/// access, computation, hit and miss is reported as an event.
+#[doc(hidden)]
+pub struct Recorder {
+    function: &'static str,
+    stats: FunctionStats,
+    /// Seconds spent on the last computation
+    last_compute_secs: f64,
+    #[cfg(feature = "tracing")]
+    _span: tracing::span::EnteredSpan,
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+impl Recorder {
+    /// Returns a recorder for the function `function` cached in `storage`.
+    pub fn new<S: ?Sized>(function: &'static str, _storage: &S) -> Self {
+        Recorder::with_storage_type(function, type_name::<S>())
+    }
+
+    /// Returns a recorder for the function `function` cached in a storage of the type `storage`,
+    /// which need not exist yet.
+    pub fn with_storage_type(function: &'static str, storage: &'static str) -> Self {
+        Recorder {
+            function,
+            stats: FunctionStats::default(),
+            last_compute_secs: 0.0,
+            #[cfg(feature = "tracing")]
+            _span: debug_span!("persistentcache", function, storage).entered(),
+        }
+    }
+
+    /// Calls `f`, which accesses the storage, and records the elapsed time. Panics if `f` fails.
+    pub fn storage<T, F: FnOnce() -> Result<T>>(&mut self, f: F) -> T {
+        let start = Instant::now();
+        let res = f();
+        let elapsed = secs(start.elapsed());
+        self.stats.storage_secs += elapsed;
+        #[cfg(feature = "tracing")]
+        match res {
+            Ok(_) => trace!(secs = elapsed, "storage access"),
+            Err(ref e) => error!(secs = elapsed, error = %e, "storage access failed"),
+        }
+        res.unwrap()
+    }
+
+    /// Calls `f`, which computes a result, and records the elapsed time.
+    pub fn compute<T, F: FnOnce() -> T>(&mut self, f: F) -> T {
+        let start = Instant::now();
+        let res = f();
+        let elapsed = secs(start.elapsed());
+        self.stats.compute_secs += elapsed;
+        self.last_compute_secs = elapsed;
+        #[cfg(feature = "tracing")]
+        trace!(secs = elapsed, "computed result");
+        res
+    }
+
+    /// Returns the seconds spent on the last computation.
+    pub fn last_compute_secs(&self) -> f64 {
+        self.last_compute_secs
+    }
+
+    /// Returns the metadata of the result computed last from the arguments `args`.
+    pub fn metadata(&self, args: Option<String>) -> Metadata {
+        Metadata::new(self.function, self.last_compute_secs, args)
+    }
+
+    /// Records a result fetched from the variable `key`.
+    pub fn hit(&mut self, key: &str, bytes: usize) {
+        self.stats.hits += 1;
+        self.stats.bytes_read += bytes as u64;
+        #[cfg(feature = "tracing")]
+        debug!(key, bytes, hit = true, "cache hit");
+    }
+
+    /// Records a computed result which was not stored in the variable `key`, because it was too
+    /// cheap to compute, too large (see `CacheOptions::min_duration` and `max_size`) or rejected
+    /// by the predicate `cache_if`.
+    pub fn skip(&mut self, key: &str, bytes: usize) {
+        self.stats.misses += 1;
+        #[cfg(feature = "tracing")]
+        debug!(key, bytes, hit = false, "cache miss, result not stored");
+    }
+
+    /// Records a result computed without accessing the storage, since caching is off.
+    pub fn bypass(&mut self) {
+        self.stats.misses += 1;
+        #[cfg(feature = "tracing")]
+        debug!(hit = false, "caching off");
+    }
+
+    /// Records a computed result and the number of bytes written to the variable `key`.
+    pub fn miss(&mut self, key: &str, bytes: usize) {
+        self.stats.misses += 1;
+        self.stats.bytes_written += bytes as u64;
+        #[cfg(feature = "tracing")]
+        debug!(key, bytes, hit = false, "cache miss");
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Ok(mut stats) = STATS.lock() {
+            stats
+                .entry(self.function.to_string())
+                .or_insert_with(FunctionStats::default)
+                .add(&self.stats);
+        }
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright 2018 Stefan Kroboth
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # CompressedStorage
+//!
+//! Wrapper around any storage which compresses values before storing them, either with zstd
+//! (feature `zstd`) or lz4 (feature `lz4`).
+//!
+//! Compressed values start with the header `HEADER` followed by a byte identifying the algorithm.
+//! Values without this header are returned unchanged, hence an existing storage can be wrapped
+//! without flushing it first and entries compressed with different algorithms can coexist. Values
+//! which cannot be decompressed, e.g. since the feature of their algorithm is disabled, are
+//! treated as missing.
+use errors::*;
+#[cfg(feature = "lz4")]
+use lz4_flex;
+use std::time::{Duration, SystemTime};
+#[cfg(feature = "zstd")]
+use zstd;
+
+use warn;
+use Codec;
+use PersistentCache;
+
+/// Magic bytes preceding the algorithm byte of every compressed value
+pub const HEADER: &[u8] = b"\xffPCZ";
+
+#[cfg(feature = "zstd")]
+const ZSTD: u8 = 1;
+#[cfg(feature = "lz4")]
+const LZ4: u8 = 2;
+
+/// Compression algorithm
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// zstd with the given level (1 to 22, 0 means the default level of zstd)
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+    /// lz4 in block format
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+/// `CompressedStorage` struct
+pub struct CompressedStorage<S: PersistentCache> {
+    /// The wrapped storage
+    inner: S,
+    /// Algorithm used for new values
+    compression: Compression,
+}
+
+impl<S: PersistentCache> CompressedStorage<S> {
+    /// Wraps the storage `inner` such that new values are compressed with `compression`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use persistentcache::storage::compressed::{CompressedStorage, Compression};
+    /// use persistentcache::storage::memory::MemoryStorage;
+    ///
+    /// #[cfg(feature = "zstd")]
+    /// let compression = Compression::Zstd(3);
+    /// #[cfg(not(feature = "zstd"))]
+    /// let compression = Compression::Lz4;
+    /// let s = CompressedStorage::new(MemoryStorage::new(), compression);
+    /// ```
+    pub fn new(inner: S, compression: Compression) -> Self {
+        CompressedStorage { inner, compression }
+    }
+
+    /// Returns the wrapped storage.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn compress(&self, val: &[u8]) -> Result<Vec<u8>> {
+        let mut out = HEADER.to_vec();
+        match self.compression {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => {
+                out.push(ZSTD);
+                out.extend(zstd::encode_all(val, level)?);
+            }
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                out.push(LZ4);
+                out.extend(lz4_flex::compress_prepend_size(val));
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Decompresses `val`, which starts with `HEADER` and the algorithm byte.
+fn decompress(val: &[u8]) -> Result<Vec<u8>> {
+    let data = &val[HEADER.len() + 1..];
+    match val[HEADER.len()] {
+        #[cfg(feature = "zstd")]
+        ZSTD => Ok(zstd::decode_all(data)?),
+        #[cfg(feature = "lz4")]
+        LZ4 => lz4_flex::decompress_size_prepended(data).chain_err(|| "Failed to decompress lz4"),
+        algorithm => bail!("Unsupported compression algorithm {}", algorithm),
+    }
+}
+
+/// Decompresses the stored value `val` of `name` if it starts with `HEADER`, otherwise `val` is
+/// returned as is. Values which cannot be decompressed, e.g. since the feature of their algorithm
+/// is disabled, are reported and treated as missing.
+fn open(name: &str, val: Vec<u8>) -> Vec<u8> {
+    if val.len() <= HEADER.len() || !val.starts_with(HEADER) {
+        return val;
+    }
+    match decompress(&val) {
+        Ok(res) => res,
+        Err(e) => {
+            warn(&format!("ignoring stored value of {}: {}", name, e));
+            vec![]
+        }
+    }
+}
+
+impl<S: PersistentCache> PersistentCache for CompressedStorage<S> {
+    /// Returns the decompressed value of the variable `name`. Values which cannot be
+    /// decompressed are treated as missing.
+    fn get(&mut self, name: &str) -> Result<Vec<u8>> {
+        Ok(open(name, self.inner.get(name)?))
+    }
+
+    /// Compresses `val` and stores it in the variable `name`.
+    fn set(&mut self, name: &str, val: &[u8]) -> Result<()> {
+        let val = self.compress(val)?;
+        self.inner.set(name, &val)
+    }
+
+    /// Flushes the wrapped storage.
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    /// Returns the codec of the wrapped storage.
+    fn codec(&self) -> Codec {
+        self.inner.codec()
+    }
+
+    /// Returns the decompressed values of the variables `names`, fetched in one batch.
+    fn get_many(&mut self, names: &[&str]) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .inner
+            .get_many(names)?
+            .into_iter()
+            .zip(names)
+            .map(|(val, name)| open(name, val))
+            .collect())
+    }
+
+    /// Compresses the values and stores them in one batch.
+    fn set_many(&mut self, entries: &[(&str, &[u8])]) -> Result<()> {
+        let vals = entries
+            .iter()
+            .map(|&(_, val)| self.compress(val))
+            .collect::<Result<Vec<_>>>()?;
+        let entries: Vec<(&str, &[u8])> = entries
+            .iter()
+            .zip(&vals)
+            .map(|(&(name, _), val)| (name, &val[..]))
+            .collect();
+        self.inner.set_many(&entries)
+    }
+
+    /// Returns the names of the variables in the wrapped storage.
+    fn keys(&mut self) -> Result<Vec<String>> {
+        self.inner.keys()
+    }
+
+    /// Deletes the variable `name` from the wrapped storage.
+    fn delete(&mut self, name: &str) -> Result<()> {
+        self.inner.delete(name)
+    }
+
+    /// Returns when the variable `name` was last written to the wrapped storage.
+    fn modified(&mut self, name: &str) -> Result<Option<SystemTime>> {
+        self.inner.modified(name)
+    }
+
+    /// Returns the remaining time to live of the variable `name` in the wrapped storage.
+    fn ttl(&mut self, name: &str) -> Result<Option<Duration>> {
+        self.inner.ttl(name)
+    }
+
+    /// Lets the variable `name` of the wrapped storage expire after `ttl`.
+    fn set_ttl(&mut self, name: &str, ttl: Duration) -> Result<()> {
+        self.inner.set_ttl(name, ttl)
+    }
+
+    /// Returns when the variable `name` was last read from the wrapped storage.
+    fn accessed(&mut self, name: &str) -> Result<Option<SystemTime>> {
+        self.inner.accessed(name)
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2018 Stefan Kroboth
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # EncryptedStorage
+//!
+//! Wrapper around any storage which encrypts values with ChaCha20-Poly1305 before storing them
+//! (feature `encryption`).
+//!
+//! Every value is stored as a random 12 byte nonce followed by the ciphertext and the
+//! authentication tag. The name of the variable is authenticated as well, hence values cannot be
+//! moved to another name unnoticed. Values which cannot be decrypted, because they were tampered
+//! with, encrypted with another key or stored unencrypted, are reported on stderr (or as a
+//! `tracing` warning) and treated as if they were not stored at all, such that they are computed
+//! again and overwritten.
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use errors::*;
+use std::time::{Duration, SystemTime};
+
+use warn;
+use Codec;
+use PersistentCache;
+
+/// Length of the nonce preceding every value
+const NONCE_LEN: usize = 12;
+
+/// `EncryptedStorage` struct
+pub struct EncryptedStorage<S: PersistentCache> {
+    /// The wrapped storage
+    inner: S,
+    /// Cipher initialized with the key
+    cipher: ChaCha20Poly1305,
+}
+
+impl<S: PersistentCache> EncryptedStorage<S> {
+    /// Wraps the storage `inner` such that all values are encrypted with the 256 bit `key`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use persistentcache::storage::encrypted::EncryptedStorage;
+    /// use persistentcache::storage::file::FileStorage;
+    ///
+    /// let key = [42; 32];
+    /// # let dir = std::env::temp_dir().join("persistentcache_example");
+    /// # let dir = dir.to_str().unwrap();
+    /// let s = EncryptedStorage::new(FileStorage::new(dir).unwrap(), &key);
+    /// ```
+    pub fn new(inner: S, key: &[u8; 32]) -> Self {
+        EncryptedStorage {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    /// Returns the wrapped storage.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn decrypt(&self, name: &str, val: &[u8]) -> Result<Vec<u8>> {
+        if val.len() < NONCE_LEN {
+            bail!("Value of {} is too short to be encrypted", name);
+        }
+        let (nonce, msg) = val.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg,
+            aad: name.as_bytes(),
+        };
+        match self.cipher.decrypt(Nonce::from_slice(nonce), payload) {
+            Ok(res) => Ok(res),
+            Err(_) => bail!("Value of {} failed authentication", name),
+        }
+    }
+
+    /// Decrypts the stored value `val` of `name`. Values which cannot be decrypted are reported
+    /// and treated as missing.
+    fn open(&self, name: &str, val: Vec<u8>) -> Vec<u8> {
+        if val.is_empty() {
+            return val;
+        }
+        match self.decrypt(name, &val) {
+            Ok(res) => res,
+            Err(e) => {
+                warn(&format!("ignoring stored value: {}", e));
+                vec![]
+            }
+        }
+    }
+
+    /// Encrypts `val` of `name` with a random nonce, which is prepended.
+    fn seal(&self, name: &str, val: &[u8]) -> Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: val,
+            aad: name.as_bytes(),
+        };
+        let mut out = nonce.to_vec();
+        match self.cipher.encrypt(&nonce, payload) {
+            Ok(res) => out.extend(res),
+            Err(_) => bail!("Failed to encrypt value of {}", name),
+        }
+        Ok(out)
+    }
+}
+
+impl<S: PersistentCache> PersistentCache for EncryptedStorage<S> {
+    /// Returns the decrypted value of the variable `name`. Values which cannot be decrypted are
+    /// reported and returned as empty.
+    fn get(&mut self, name: &str) -> Result<Vec<u8>> {
+        let val = self.inner.get(name)?;
+        Ok(self.open(name, val))
+    }
+
+    /// Encrypts `val` and stores it in the variable `name`.
+    fn set(&mut self, name: &str, val: &[u8]) -> Result<()> {
+        let val = self.seal(name, val)?;
+        self.inner.set(name, &val)
+    }
+
+    /// Flushes the wrapped storage.
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    /// Returns the codec of the wrapped storage.
+    fn codec(&self) -> Codec {
+        self.inner.codec()
+    }
+
+    /// Returns the decrypted values of the variables `names`, fetched in one batch.
+    fn get_many(&mut self, names: &[&str]) -> Result<Vec<Vec<u8>>> {
+        let vals = self.inner.get_many(names)?;
+        Ok(names
+            .iter()
+            .zip(vals)
+            .map(|(name, val)| self.open(name, val))
+            .collect())
+    }
+
+    /// Encrypts the values and stores them in one batch.
+    fn set_many(&mut self, entries: &[(&str, &[u8])]) -> Result<()> {
+        let vals = entries
+            .iter()
+            .map(|&(name, val)| self.seal(name, val))
+            .collect::<Result<Vec<_>>>()?;
+        let entries: Vec<(&str, &[u8])> = entries
+            .iter()
+            .zip(&vals)
+            .map(|(&(name, _), val)| (name, &val[..]))
+            .collect();
+        self.inner.set_many(&entries)
+    }
+
+    /// Returns the names of the variables in the wrapped storage.
+    fn keys(&mut self) -> Result<Vec<String>> {
+        self.inner.keys()
+    }
+
+    /// Deletes the variable `name` from the wrapped storage.
+    fn delete(&mut self, name: &str) -> Result<()> {
+        self.inner.delete(name)
+    }
+
+    /// Returns when the variable `name` was last written to the wrapped storage.
+    fn modified(&mut self, name: &str) -> Result<Option<SystemTime>> {
+        self.inner.modified(name)
+    }
+
+    /// Returns the remaining time to live of the variable `name` in the wrapped storage.
+    fn ttl(&mut self, name: &str) -> Result<Option<Duration>> {
+        self.inner.ttl(name)
+    }
+
+    /// Lets the variable `name` of the wrapped storage expire after `ttl`.
+    fn set_ttl(&mut self, name: &str, ttl: Duration) -> Result<()> {
+        self.inner.set_ttl(name, ttl)
+    }
+
+    /// Returns when the variable `name` was last read from the wrapped storage.
+    fn accessed(&mut self, name: &str) -> Result<Option<SystemTime>> {
+        self.inner.accessed(name)
+    }
+}
@@ -13,10 +13,14 @@ use errors::*;
 use fs2::FileExt;
 use regex::Regex;
 use std::error::Error;
-use std::fs::{create_dir_all, read_dir, remove_file, File};
+use std::fs::{create_dir_all, metadata, read_dir, remove_file, File};
 use std::io::prelude::*;
+use std::io::ErrorKind;
 use std::path::Path;
+use std::time::SystemTime;
 
+use config;
+use Codec;
 use PersistentCache;
 #[allow(unused_imports)]
 use PREFIX;
@@ -25,6 +29,8 @@ use PREFIX;
 pub struct FileStorage {
     /// Indicates where files are saved
     path: String,
+    /// Codec of the stored values
+    codec: Codec,
 }
 
 impl FileStorage {
@@ -35,15 +41,40 @@ impl FileStorage {
     /// ```
     /// use persistentcache::storage::file::FileStorage;
     ///
-    /// let s = FileStorage::new(".example_dir").unwrap();
+    /// # let dir = std::env::temp_dir().join("persistentcache_example");
+    /// # let dir = dir.to_str().unwrap();
+    /// let s = FileStorage::new(dir).unwrap();
     /// ```
     // pub fn new(path: &'a str) -> Result<Self, Box<Error>> {
     pub fn new(path: &str) -> Result<Self> {
         create_dir_all(path)?;
         Ok(FileStorage {
             path: path.to_owned(),
+            codec: Codec::default(),
         })
     }
+
+    /// Creates a `FileStorage` in the configured directory, see the `config` module.
+    pub fn from_config() -> Result<Self> {
+        Self::new(&config::dir())
+    }
+
+    /// Stores all values with `codec` unless a function chooses another one.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use persistentcache::Codec;
+    /// use persistentcache::storage::file::FileStorage;
+    ///
+    /// # let dir = std::env::temp_dir().join("persistentcache_example");
+    /// # let dir = dir.to_str().unwrap();
+    /// let s = FileStorage::new(dir).unwrap().with_codec(Codec::Bincode);
+    /// ```
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
 }
 
 impl PersistentCache for FileStorage {
@@ -102,4 +133,49 @@ impl PersistentCache for FileStorage {
         }
         Ok(())
     }
+
+    /// Returns the codec of the stored values.
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Returns the names of all files in `path` which start with `PREFIX_`.
+    fn keys(&mut self) -> Result<Vec<String>> {
+        let prefix = format!("{}_", PREFIX);
+        let mut keys = vec![];
+        for file in read_dir(&self.path)? {
+            if let Some(name) = file?.file_name().to_str() {
+                if name.starts_with(&prefix) {
+                    keys.push(name.to_string());
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Deletes the file corresponding to the variable `name`.
+    fn delete(&mut self, name: &str) -> Result<()> {
+        match remove_file(format!("{}/{}", self.path, name)) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            res => Ok(res?),
+        }
+    }
+
+    /// Returns the modification time of the file corresponding to the variable `name`.
+    fn modified(&mut self, name: &str) -> Result<Option<SystemTime>> {
+        match metadata(format!("{}/{}", self.path, name)) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            res => Ok(Some(res?.modified()?)),
+        }
+    }
+
+    /// Returns the access time of the file corresponding to the variable `name`, if the
+    /// filesystem records it.
+    fn accessed(&mut self, name: &str) -> Result<Option<SystemTime>> {
+        match metadata(format!("{}/{}", self.path, name)) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            res => Ok(res?.accessed().ok()),
+        }
+    }
 }
@@ -11,26 +11,25 @@
 //! This storage also stores the data in a HashMap in memory. If the data is available in the
 //! HashMap, it will be retreived from there, otherwise it will be retreived from disk.
 //! Once a value is retreived from disk, it is also stored in the HashMap.
+//! It is a shorthand for a `TieredStorage` of a `MemoryStorage` over a `FileStorage` with
+//! `Policy::WriteThrough`.
 
 use errors::*;
-use fs2::FileExt;
-use regex::Regex;
-use std::collections::HashMap;
-use std::error::Error;
-use std::fs::{create_dir_all, read_dir, remove_file, File};
-use std::io::prelude::*;
-use std::path::Path;
+use std::time::{Duration, SystemTime};
 
+use config;
+use storage::file::FileStorage;
+use storage::memory::MemoryStorage;
+use storage::tiered::{Policy, TieredStorage};
+use Codec;
 use PersistentCache;
-#[allow(unused_imports)]
-use PREFIX;
 
 /// `FileMemoryStorage` struct
 pub struct FileMemoryStorage {
-    /// Indicates where files are saved
-    path: String,
-    /// HashMap storing all values alongside the disk
-    mem: HashMap<String, Vec<u8>>,
+    /// Memory over disk
+    tiers: TieredStorage<MemoryStorage, FileStorage>,
+    /// Codec of the stored values
+    codec: Codec,
 }
 
 impl FileMemoryStorage {
@@ -41,15 +40,42 @@ impl FileMemoryStorage {
     /// ```
     /// use persistentcache::storage::file_memory::FileMemoryStorage;
     ///
-    /// let mut s = FileMemoryStorage::new(".example_dir").unwrap();
+    /// # let dir = std::env::temp_dir().join("persistentcache_example");
+    /// # let dir = dir.to_str().unwrap();
+    /// let mut s = FileMemoryStorage::new(dir).unwrap();
     /// ```
     pub fn new(path: &str) -> Result<Self> {
-        create_dir_all(path)?;
         Ok(FileMemoryStorage {
-            path: path.to_owned(),
-            mem: HashMap::new(),
+            tiers: TieredStorage::new(
+                MemoryStorage::new(),
+                FileStorage::new(path)?,
+                Policy::WriteThrough,
+            ),
+            codec: Codec::default(),
         })
     }
+
+    /// Creates a `FileMemoryStorage` in the configured directory, see the `config` module.
+    pub fn from_config() -> Result<Self> {
+        Self::new(&config::dir())
+    }
+
+    /// Stores all values with `codec` unless a function chooses another one.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use persistentcache::Codec;
+    /// use persistentcache::storage::file_memory::FileMemoryStorage;
+    ///
+    /// # let dir = std::env::temp_dir().join("persistentcache_example");
+    /// # let dir = dir.to_str().unwrap();
+    /// let s = FileMemoryStorage::new(dir).unwrap().with_codec(Codec::Bincode);
+    /// ```
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
 }
 
 impl PersistentCache for FileMemoryStorage {
@@ -57,71 +83,63 @@ impl PersistentCache for FileMemoryStorage {
     /// If it is stored in the hash map, it will retreive it from there, otherwise it will retreive
     /// it from the file system.
     fn get(&mut self, name: &str) -> Result<Vec<u8>> {
-        if self.mem.contains_key(&name.to_string()) {
-            Ok(self.mem.get(&name.to_string()).unwrap().clone())
-        } else {
-            let fpath = format!("{}/{}", self.path, name);
-            let p = Path::new(&fpath);
-            let mut file = match File::open(&p) {
-                Err(_) => return Ok(vec![]),
-                Ok(f) => f,
-            };
-            file.lock_exclusive()?;
-            let mut s: Vec<u8> = Vec::new();
-            match file.read_to_end(&mut s) {
-                Ok(_) => {
-                    file.unlock()?;
-                    // also store in HashMap
-                    self.mem.insert(name.to_string(), s.to_vec());
-                    Ok(s.to_vec())
-                }
-                Err(e) => {
-                    file.unlock()?;
-                    Err(e.into())
-                }
-            }
-        }
+        self.tiers.get(name)
     }
 
-    /// Writes the data of type `&[u8]` in array `val` to the file corresponding to the variable `name`.
+    /// Writes `val` to the hash map and to the file corresponding to the variable `name`.
     fn set(&mut self, name: &str, val: &[u8]) -> Result<()> {
-        // Write into hash map
-        self.mem.insert(name.to_string(), val.to_vec());
-
-        // Write to file
-        let fpath = format!("{}/{}", self.path, name);
-        let p = Path::new(&fpath);
-        let mut file = match File::create(&p) {
-            Err(e) => return Err(e.into()),
-            Ok(f) => f,
-        };
-
-        file.lock_exclusive()?;
-        file.write_all(val)?;
-        file.unlock()?;
-        Ok(())
+        self.tiers.set(name, val)
     }
 
-    /// Delete all variables stored in `path` (see `new()`) which start with `PREFIX_`.
+    /// Delete all variables stored in memory and in `path` (see `new()`) which start with
+    /// `PREFIX_`.
     fn flush(&mut self) -> Result<()> {
-        // clear memory
-        self.mem.clear();
-
-        // remove files
-        let p = Path::new(&self.path);
-        match read_dir(p) {
-            Err(e) => return Err(e.into()),
-            Ok(iterator) => {
-                let re = Regex::new(&format!(r"^{}/{}_", self.path, PREFIX))?;
-                for file in iterator {
-                    let tmp = file?.path();
-                    let f = tmp.to_str().unwrap();
-                    if re.is_match(f) {
-                        remove_file(f)?
-                    }
-                }
-            }
-        }
-        Ok(())
+        self.tiers.flush()
+    }
+
+    /// Returns the codec of the stored values.
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Returns the values of the variables `names`, reading those missing in memory from disk.
+    fn get_many(&mut self, names: &[&str]) -> Result<Vec<Vec<u8>>> {
+        self.tiers.get_many(names)
+    }
+
+    /// Writes several values to the hash map and to disk.
+    fn set_many(&mut self, entries: &[(&str, &[u8])]) -> Result<()> {
+        self.tiers.set_many(entries)
+    }
+
+    /// Returns the names of all variables which start with `PREFIX_`.
+    fn keys(&mut self) -> Result<Vec<String>> {
+        self.tiers.keys()
+    }
+
+    /// Deletes the variable `name` from memory and disk.
+    fn delete(&mut self, name: &str) -> Result<()> {
+        self.tiers.delete(name)
+    }
+
+    /// Returns the modification time of the file corresponding to the variable `name`.
+    fn modified(&mut self, name: &str) -> Result<Option<SystemTime>> {
+        self.tiers.modified(name)
+    }
+
+    /// Returns the remaining time to live of the variable `name`.
+    fn ttl(&mut self, name: &str) -> Result<Option<Duration>> {
+        self.tiers.ttl(name)
+    }
+
+    /// Lets the variable `name` expire after `ttl`.
+    fn set_ttl(&mut self, name: &str, ttl: Duration) -> Result<()> {
+        self.tiers.set_ttl(name, ttl)
+    }
+
+    /// Returns the access time of the file corresponding to the variable `name`, if the
+    /// filesystem records it.
+    fn accessed(&mut self, name: &str) -> Result<Option<SystemTime>> {
+        self.tiers.accessed(name)
     }
 }
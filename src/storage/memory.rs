@@ -0,0 +1,100 @@
+// Copyright 2018 Stefan Kroboth
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # MemoryStorage
+//!
+//! Storage for saving return values of functions in a HashMap in memory. This storage is not
+//! persistent on its own, it is meant as the fast tier of a `TieredStorage`.
+use errors::*;
+use std::collections::HashMap;
+
+use Codec;
+use PersistentCache;
+use PREFIX;
+
+/// `MemoryStorage` struct
+#[derive(Default)]
+pub struct MemoryStorage {
+    /// HashMap storing all values
+    mem: HashMap<String, Vec<u8>>,
+    /// Codec of the stored values
+    codec: Codec,
+}
+
+impl MemoryStorage {
+    /// Returns an empty `MemoryStorage`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use persistentcache::storage::memory::MemoryStorage;
+    ///
+    /// let s = MemoryStorage::new();
+    /// ```
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+
+    /// Stores all values with `codec` unless a function chooses another one.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use persistentcache::Codec;
+    /// use persistentcache::storage::memory::MemoryStorage;
+    ///
+    /// let s = MemoryStorage::new().with_codec(Codec::Bincode);
+    /// ```
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+}
+
+impl PersistentCache for MemoryStorage {
+    /// Returns the value corresponding to the variable `name`.
+    fn get(&mut self, name: &str) -> Result<Vec<u8>> {
+        Ok(self.mem.get(name).cloned().unwrap_or_default())
+    }
+
+    /// Stores `val` in the variable `name`.
+    fn set(&mut self, name: &str, val: &[u8]) -> Result<()> {
+        self.mem.insert(name.to_string(), val.to_vec());
+        Ok(())
+    }
+
+    /// Delete all variables which start with `PREFIX_`.
+    fn flush(&mut self) -> Result<()> {
+        let prefix = format!("{}_", PREFIX);
+        self.mem.retain(|name, _| !name.starts_with(&prefix));
+        Ok(())
+    }
+
+    /// Returns the codec of the stored values.
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Returns the names of all variables which start with `PREFIX_`.
+    fn keys(&mut self) -> Result<Vec<String>> {
+        let prefix = format!("{}_", PREFIX);
+        let mut keys: Vec<String> = self
+            .mem
+            .keys()
+            .filter(|name| name.starts_with(&prefix))
+            .cloned()
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Deletes the variable `name`.
+    fn delete(&mut self, name: &str) -> Result<()> {
+        self.mem.remove(name);
+        Ok(())
+    }
+}
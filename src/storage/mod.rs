@@ -6,16 +6,38 @@
 // copied, modified, or distributed except according to those terms.
 
 //! Implementation of different persistent storages. Currently on disk (`FileStorage` and
-//! `FileMemoryStorage`) and in Redis (`RedisStorage`).
+//! `FileMemoryStorage`), in memory (`MemoryStorage`) and in Redis (`RedisStorage`).
+//! `CompressedStorage` compresses and `EncryptedStorage` encrypts the values of any storage,
+//! `TieredStorage` combines a fast and a slow storage and `WriteBehindStorage` writes values in a
+//! background thread.
 
+/// `CompressedStorage`
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+pub mod compressed;
+/// `EncryptedStorage`
+#[cfg(feature = "encryption")]
+pub mod encrypted;
 /// `FileStorage`
 pub mod file;
 /// `FileMemoryStorage`
 pub mod file_memory;
+/// `MemoryStorage`
+pub mod memory;
 /// `RedisStorage`
 pub mod redis;
+/// `TieredStorage`
+pub mod tiered;
+/// `WriteBehindStorage`
+pub mod write_behind;
 
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+pub use storage::compressed::CompressedStorage;
+#[cfg(feature = "encryption")]
+pub use storage::encrypted::EncryptedStorage;
 pub use storage::file::FileStorage;
 pub use storage::file_memory::FileMemoryStorage;
+pub use storage::memory::MemoryStorage;
 /// Bring them into scope
 pub use storage::redis::RedisStorage;
+pub use storage::tiered::TieredStorage;
+pub use storage::write_behind::WriteBehindStorage;
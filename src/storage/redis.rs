@@ -11,7 +11,10 @@
 use errors::*;
 use redis::{self, Commands};
 use std::error::Error;
+use std::time::{Duration, SystemTime};
 
+use config;
+use Codec;
 use PersistentCache;
 #[allow(unused_imports)]
 use PREFIX;
@@ -19,6 +22,8 @@ use PREFIX;
 /// `RedisStorage` struct holds a `redis::Connection` variable.
 pub struct RedisStorage {
     con: redis::Connection,
+    /// Codec of the stored values
+    codec: Codec,
 }
 
 impl RedisStorage {
@@ -37,7 +42,30 @@ impl RedisStorage {
     pub fn new(host: &str) -> Result<Self> {
         let client = redis::Client::open(host)?;
         let con = client.get_connection()?;
-        Ok(RedisStorage { con })
+        Ok(RedisStorage {
+            con,
+            codec: Codec::default(),
+        })
+    }
+
+    /// Connects to the configured Redis server, see the `config` module.
+    pub fn from_config() -> Result<Self> {
+        Self::new(&config::redis_url())
+    }
+
+    /// Stores all values with `codec` unless a function chooses another one.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use persistentcache::Codec;
+    /// use persistentcache::storage::redis::RedisStorage;
+    ///
+    /// let s = RedisStorage::new("redis://127.0.0.1").unwrap().with_codec(Codec::Bincode);
+    /// ```
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
     }
 }
 
@@ -79,4 +107,86 @@ impl PersistentCache for RedisStorage {
         }
         Ok(())
     }
+
+    /// Returns the codec of the stored values.
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Returns the values of the Redis variables `names` with a single `MGET`.
+    fn get_many(&mut self, names: &[&str]) -> Result<Vec<Vec<u8>>> {
+        if names.is_empty() {
+            return Ok(vec![]);
+        }
+        let r: Result<Vec<Vec<u8>>> = redis::cmd("MGET")
+            .arg(names)
+            .query(&mut self.con)
+            .map_err(|e| e.into());
+        r
+    }
+
+    /// Sets several Redis variables in one pipeline.
+    fn set_many(&mut self, entries: &[(&str, &[u8])]) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for &(name, val) in entries {
+            pipe.set(name, val).ignore();
+        }
+        let r: Result<()> = pipe.query(&mut self.con).map_err(|e| e.into());
+        r
+    }
+
+    /// Returns the names of all Redis variables which start with `PREFIX_`.
+    fn keys(&mut self) -> Result<Vec<String>> {
+        let r: Result<Vec<String>> = redis::cmd("KEYS")
+            .arg(format!("{}_*", PREFIX))
+            .query(&mut self.con)
+            .map_err(|e| e.into());
+        let mut keys = r?;
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Deletes the Redis variable `name`.
+    fn delete(&mut self, name: &str) -> Result<()> {
+        let r: Result<()> = self.con.del(name).map_err(|e| e.into());
+        r
+    }
+
+    /// Returns the remaining time to live of the Redis variable `name`, `None` if it does not
+    /// expire or does not exist.
+    fn ttl(&mut self, name: &str) -> Result<Option<Duration>> {
+        let r: Result<i64> = redis::cmd("PTTL")
+            .arg(name)
+            .query(&mut self.con)
+            .map_err(|e| e.into());
+        let millis = r?;
+        if millis < 0 {
+            return Ok(None);
+        }
+        Ok(Some(Duration::from_millis(millis as u64)))
+    }
+
+    /// Lets the Redis variable `name` expire after `ttl`.
+    fn set_ttl(&mut self, name: &str, ttl: Duration) -> Result<()> {
+        let millis = ttl.as_secs() * 1000 + u64::from(ttl.subsec_millis());
+        let r: Result<()> = redis::cmd("PEXPIRE")
+            .arg(name)
+            .arg(millis)
+            .query(&mut self.con)
+            .map_err(|e| e.into());
+        r
+    }
+
+    /// Returns when the Redis variable `name` was last read or written, based on its idle time.
+    fn accessed(&mut self, name: &str) -> Result<Option<SystemTime>> {
+        let r: Result<Option<u64>> = redis::cmd("OBJECT")
+            .arg("IDLETIME")
+            .arg(name)
+            .query(&mut self.con)
+            .map_err(|e| e.into());
+        Ok(r?.map(|idle| SystemTime::now() - Duration::from_secs(idle)))
+    }
 }
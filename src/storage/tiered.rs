@@ -0,0 +1,225 @@
+// Copyright 2018 Stefan Kroboth
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # TieredStorage
+//!
+//! Combines a fast, usually local storage (L1) with a slow, usually shared storage (L2), e.g. a
+//! `MemoryStorage` over a `RedisStorage` or a `FileStorage` on a local disk over one on a network
+//! filesystem.
+//!
+//! Values are looked up in L1 first. Values found in L2 only are promoted, i.e. copied into L1.
+//! Where values are written depends on the `Policy`.
+use errors::*;
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+use warn;
+use Codec;
+use PersistentCache;
+
+/// Where `TieredStorage` writes values to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Values are written to L2 only. L1 is filled by promotion when values are read.
+    ReadThrough,
+    /// Values are written to L1 and L2.
+    WriteThrough,
+    /// Values are written to L1 and copied to L2 on `sync()` or when the storage is dropped.
+    WriteBack,
+}
+
+/// `TieredStorage` struct
+pub struct TieredStorage<L1: PersistentCache, L2: PersistentCache> {
+    /// Fast tier
+    l1: L1,
+    /// Slow tier
+    l2: L2,
+    /// Where values are written to
+    policy: Policy,
+    /// Names of the values which still need to be written to L2 (only for `Policy::WriteBack`)
+    dirty: HashSet<String>,
+}
+
+impl<L1: PersistentCache, L2: PersistentCache> TieredStorage<L1, L2> {
+    /// Combines the fast storage `l1` and the slow storage `l2`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use persistentcache::storage::file::FileStorage;
+    /// use persistentcache::storage::memory::MemoryStorage;
+    /// use persistentcache::storage::tiered::{Policy, TieredStorage};
+    ///
+    /// # let dir = std::env::temp_dir().join("persistentcache_example");
+    /// # let dir = dir.to_str().unwrap();
+    /// let l2 = FileStorage::new(dir).unwrap();
+    /// let s = TieredStorage::new(MemoryStorage::new(), l2, Policy::WriteThrough);
+    /// ```
+    pub fn new(l1: L1, l2: L2, policy: Policy) -> Self {
+        TieredStorage {
+            l1,
+            l2,
+            policy,
+            dirty: HashSet::new(),
+        }
+    }
+
+    /// Writes all values which are only stored in L1 to L2.
+    pub fn sync(&mut self) -> Result<()> {
+        let names: Vec<String> = self.dirty.drain().collect();
+        let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+        let vals = self.l1.get_many(&names)?;
+        let entries: Vec<(&str, &[u8])> = names
+            .iter()
+            .zip(&vals)
+            .filter(|&(_, val)| !val.is_empty())
+            .map(|(&name, val)| (name, &val[..]))
+            .collect();
+        self.l2.set_many(&entries)
+    }
+}
+
+impl<L1: PersistentCache, L2: PersistentCache> PersistentCache for TieredStorage<L1, L2> {
+    /// Returns the value of the variable `name` from L1 or, if it is not stored there, from L2.
+    fn get(&mut self, name: &str) -> Result<Vec<u8>> {
+        let val = self.l1.get(name)?;
+        if !val.is_empty() {
+            return Ok(val);
+        }
+        let val = self.l2.get(name)?;
+        if !val.is_empty() {
+            self.l1.set(name, &val)?;
+        }
+        Ok(val)
+    }
+
+    /// Stores `val` in the variable `name` according to the policy.
+    fn set(&mut self, name: &str, val: &[u8]) -> Result<()> {
+        match self.policy {
+            Policy::ReadThrough => self.l2.set(name, val),
+            Policy::WriteThrough => {
+                self.l1.set(name, val)?;
+                self.l2.set(name, val)
+            }
+            Policy::WriteBack => {
+                self.l1.set(name, val)?;
+                self.dirty.insert(name.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    /// Flushes both tiers. Values not yet written to L2 are discarded.
+    fn flush(&mut self) -> Result<()> {
+        self.dirty.clear();
+        self.l1.flush()?;
+        self.l2.flush()
+    }
+
+    /// Returns the codec of L2.
+    fn codec(&self) -> Codec {
+        self.l2.codec()
+    }
+
+    /// Returns the values of the variables `names`. Values missing in L1 are fetched from L2 in
+    /// one batch.
+    fn get_many(&mut self, names: &[&str]) -> Result<Vec<Vec<u8>>> {
+        let mut vals = self.l1.get_many(names)?;
+        let missing: Vec<usize> = (0..names.len()).filter(|&i| vals[i].is_empty()).collect();
+        if missing.is_empty() {
+            return Ok(vals);
+        }
+        let missing_names: Vec<&str> = missing.iter().map(|&i| names[i]).collect();
+        let found = self.l2.get_many(&missing_names)?;
+        {
+            let promoted: Vec<(&str, &[u8])> = missing_names
+                .iter()
+                .zip(&found)
+                .filter(|&(_, val)| !val.is_empty())
+                .map(|(&name, val)| (name, &val[..]))
+                .collect();
+            self.l1.set_many(&promoted)?;
+        }
+        for (i, val) in missing.into_iter().zip(found) {
+            vals[i] = val;
+        }
+        Ok(vals)
+    }
+
+    /// Stores several values according to the policy.
+    fn set_many(&mut self, entries: &[(&str, &[u8])]) -> Result<()> {
+        match self.policy {
+            Policy::ReadThrough => self.l2.set_many(entries),
+            Policy::WriteThrough => {
+                self.l1.set_many(entries)?;
+                self.l2.set_many(entries)
+            }
+            Policy::WriteBack => {
+                self.l1.set_many(entries)?;
+                for &(name, _) in entries {
+                    self.dirty.insert(name.to_string());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the names of the variables stored in either tier.
+    fn keys(&mut self) -> Result<Vec<String>> {
+        let mut keys = self.l1.keys()?;
+        keys.extend(self.l2.keys()?);
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// Deletes the variable `name` from both tiers.
+    fn delete(&mut self, name: &str) -> Result<()> {
+        self.dirty.remove(name);
+        self.l1.delete(name)?;
+        self.l2.delete(name)
+    }
+
+    /// Returns when the variable `name` was last written to L1 or, if L1 does not know, to L2.
+    fn modified(&mut self, name: &str) -> Result<Option<SystemTime>> {
+        match self.l1.modified(name)? {
+            Some(time) => Ok(Some(time)),
+            None => self.l2.modified(name),
+        }
+    }
+
+    /// Returns the remaining time to live of the variable `name` in L1 or, if it does not expire
+    /// there, in L2.
+    fn ttl(&mut self, name: &str) -> Result<Option<Duration>> {
+        match self.l1.ttl(name)? {
+            Some(ttl) => Ok(Some(ttl)),
+            None => self.l2.ttl(name),
+        }
+    }
+
+    /// Lets the variable `name` expire after `ttl` in both tiers.
+    fn set_ttl(&mut self, name: &str, ttl: Duration) -> Result<()> {
+        self.l1.set_ttl(name, ttl)?;
+        self.l2.set_ttl(name, ttl)
+    }
+
+    /// Returns when the variable `name` was last read from L1 or, if L1 does not know, from L2.
+    fn accessed(&mut self, name: &str) -> Result<Option<SystemTime>> {
+        match self.l1.accessed(name)? {
+            Some(time) => Ok(Some(time)),
+            None => self.l2.accessed(name),
+        }
+    }
+}
+
+impl<L1: PersistentCache, L2: PersistentCache> Drop for TieredStorage<L1, L2> {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            warn(&format!("failed to write values to L2: {}", e));
+        }
+    }
+}
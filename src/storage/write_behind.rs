@@ -0,0 +1,255 @@
+// Copyright 2018 Stefan Kroboth
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # WriteBehindStorage
+//!
+//! Wrapper around any storage which writes values in a background thread, such that `set`
+//! returns without waiting for the disk or the network.
+//!
+//! Values are put into a queue of bounded capacity. If the queue is full, `set` blocks until the
+//! writer caught up. The writer takes all queued values at once and writes them with a single
+//! `set_many`. Values which are not written yet are still returned by `get`. `sync()` waits until
+//! all queued values are written and reports the first error which occurred since the last call.
+//! Dropping the storage syncs as well.
+use errors::*;
+use std::collections::HashMap;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use warn;
+use Codec;
+use PersistentCache;
+
+/// Message to the writer thread
+enum Message {
+    /// Write the value to the variable
+    Set(String, Vec<u8>),
+    /// Reply with the first error since the last sync once all previous values are written
+    Sync(SyncSender<Option<Error>>),
+}
+
+/// `WriteBehindStorage` struct
+pub struct WriteBehindStorage<S: PersistentCache + Send + 'static> {
+    /// The wrapped storage, shared with the writer thread
+    inner: Arc<Mutex<S>>,
+    /// Values which are queued but not written yet
+    pending: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    /// Queue of the writer thread
+    sender: Option<SyncSender<Message>>,
+    /// Writer thread
+    writer: Option<JoinHandle<()>>,
+    /// Codec of the wrapped storage
+    codec: Codec,
+}
+
+impl<S: PersistentCache + Send + 'static> WriteBehindStorage<S> {
+    /// Wraps the storage `inner`. At most `capacity` values are queued before `set` blocks.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use persistentcache::storage::file::FileStorage;
+    /// use persistentcache::storage::write_behind::WriteBehindStorage;
+    ///
+    /// # let dir = std::env::temp_dir().join("persistentcache_example");
+    /// # let dir = dir.to_str().unwrap();
+    /// let s = WriteBehindStorage::new(FileStorage::new(dir).unwrap(), 1000);
+    /// ```
+    pub fn new(inner: S, capacity: usize) -> Self {
+        let codec = inner.codec();
+        let inner = Arc::new(Mutex::new(inner));
+        let pending = Arc::new(Mutex::new(HashMap::new()));
+        let (sender, receiver) = sync_channel(capacity);
+        let writer = {
+            let inner = inner.clone();
+            let pending = pending.clone();
+            thread::spawn(move || write_loop(&inner, &pending, &receiver))
+        };
+        WriteBehindStorage {
+            inner,
+            pending,
+            sender: Some(sender),
+            writer: Some(writer),
+            codec,
+        }
+    }
+
+    /// Waits until all queued values are written. Returns the first error which occurred while
+    /// writing since the last call.
+    pub fn sync(&mut self) -> Result<()> {
+        let (reply, result) = sync_channel(1);
+        self.send(Message::Sync(reply))?;
+        match result.recv() {
+            Ok(None) => Ok(()),
+            Ok(Some(e)) => Err(e),
+            Err(_) => bail!("Writer thread terminated"),
+        }
+    }
+
+    fn send(&self, message: Message) -> Result<()> {
+        match self.sender.as_ref().unwrap().send(message) {
+            Ok(()) => Ok(()),
+            Err(_) => bail!("Writer thread terminated"),
+        }
+    }
+}
+
+/// Writes the queued values until the queue is closed.
+fn write_loop<S: PersistentCache>(
+    inner: &Mutex<S>,
+    pending: &Mutex<HashMap<String, Vec<u8>>>,
+    receiver: &Receiver<Message>,
+) {
+    let mut error = None;
+    while let Ok(first) = receiver.recv() {
+        // Take everything which is queued right now and write it in batches
+        let mut batch = vec![];
+        for message in Some(first).into_iter().chain(receiver.try_iter()) {
+            match message {
+                Message::Set(name, val) => batch.push((name, val)),
+                Message::Sync(reply) => {
+                    if let Err(e) = write_batch(inner, pending, &mut batch) {
+                        error = error.or(Some(e));
+                    }
+                    let _ = reply.send(error.take());
+                }
+            }
+        }
+        if let Err(e) = write_batch(inner, pending, &mut batch) {
+            error = error.or(Some(e));
+        }
+    }
+}
+
+/// Writes and empties `batch`.
+fn write_batch<S: PersistentCache>(
+    inner: &Mutex<S>,
+    pending: &Mutex<HashMap<String, Vec<u8>>>,
+    batch: &mut Vec<(String, Vec<u8>)>,
+) -> Result<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let result = {
+        let entries: Vec<(&str, &[u8])> = batch
+            .iter()
+            .map(|(name, val)| (name.as_str(), &val[..]))
+            .collect();
+        inner.lock().unwrap().set_many(&entries)
+    };
+    let mut pending = pending.lock().unwrap();
+    for (name, val) in batch.drain(..) {
+        // The value may have been replaced in the meantime
+        if pending.get(&name) == Some(&val) {
+            pending.remove(&name);
+        }
+    }
+    result
+}
+
+impl<S: PersistentCache + Send + 'static> PersistentCache for WriteBehindStorage<S> {
+    /// Returns the value of the variable `name`, also if it is not written yet.
+    fn get(&mut self, name: &str) -> Result<Vec<u8>> {
+        if let Some(val) = self.pending.lock().unwrap().get(name) {
+            return Ok(val.clone());
+        }
+        self.inner.lock().unwrap().get(name)
+    }
+
+    /// Queues `val` to be written to the variable `name`. Blocks if the queue is full.
+    fn set(&mut self, name: &str, val: &[u8]) -> Result<()> {
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), val.to_vec());
+        self.send(Message::Set(name.to_string(), val.to_vec()))
+    }
+
+    /// Waits until all queued values are written and flushes the wrapped storage.
+    fn flush(&mut self) -> Result<()> {
+        // Errors of values which are deleted anyway do not matter
+        let _ = self.sync();
+        self.inner.lock().unwrap().flush()
+    }
+
+    /// Returns the codec of the wrapped storage.
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Returns the values of the variables `names`. Values which are not written yet are taken
+    /// from the queue, all others are fetched in one batch.
+    fn get_many(&mut self, names: &[&str]) -> Result<Vec<Vec<u8>>> {
+        let mut vals = {
+            let pending = self.pending.lock().unwrap();
+            names
+                .iter()
+                .map(|&name| pending.get(name).cloned())
+                .collect::<Vec<_>>()
+        };
+        let missing: Vec<&str> = names
+            .iter()
+            .zip(&vals)
+            .filter(|&(_, val)| val.is_none())
+            .map(|(&name, _)| name)
+            .collect();
+        let mut found = self.inner.lock().unwrap().get_many(&missing)?.into_iter();
+        Ok(vals
+            .drain(..)
+            .map(|val| val.unwrap_or_else(|| found.next().unwrap_or_default()))
+            .collect())
+    }
+
+    /// Waits until all queued values are written and returns the names of the variables in the
+    /// wrapped storage.
+    fn keys(&mut self) -> Result<Vec<String>> {
+        self.sync()?;
+        self.inner.lock().unwrap().keys()
+    }
+
+    /// Waits until all queued values are written and deletes the variable `name`.
+    fn delete(&mut self, name: &str) -> Result<()> {
+        self.sync()?;
+        self.inner.lock().unwrap().delete(name)
+    }
+
+    /// Returns when the variable `name` was last written to the wrapped storage.
+    fn modified(&mut self, name: &str) -> Result<Option<SystemTime>> {
+        self.inner.lock().unwrap().modified(name)
+    }
+
+    /// Returns the remaining time to live of the variable `name` in the wrapped storage.
+    fn ttl(&mut self, name: &str) -> Result<Option<Duration>> {
+        self.inner.lock().unwrap().ttl(name)
+    }
+
+    /// Waits until all queued values are written and lets the variable `name` expire after `ttl`.
+    fn set_ttl(&mut self, name: &str, ttl: Duration) -> Result<()> {
+        self.sync()?;
+        self.inner.lock().unwrap().set_ttl(name, ttl)
+    }
+
+    /// Returns when the variable `name` was last read from the wrapped storage.
+    fn accessed(&mut self, name: &str) -> Result<Option<SystemTime>> {
+        self.inner.lock().unwrap().accessed(name)
+    }
+}
+
+impl<S: PersistentCache + Send + 'static> Drop for WriteBehindStorage<S> {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            warn(&format!("failed to write values: {}", e));
+        }
+        // Closing the queue terminates the writer thread
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}